@@ -0,0 +1,429 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Admin endpoints (`/admin/v1/...`) to inspect, purge and warm the
+//! statistics cache. They are only served when an admin token is configured
+//! (`--admin-token`), and require it as an `Authorization: Bearer` header.
+
+use actix_web::{
+    delete, get,
+    http::header::{AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE},
+    post, web, HttpRequest, HttpResponse,
+};
+use tokei::Language;
+
+use crate::{
+    api::LanguageSummary, history, resolve_repository, warm_statistics, AppConfig, Resolution,
+    ResolvedRepository, StatisticsQuery, Warming, BUSY_RETRY_AFTER_SECONDS,
+};
+
+/// Selects cache entries by repository, or by exact cache key.
+#[derive(serde::Deserialize)]
+pub struct CacheFilter {
+    domain: Option<String>,
+    user: Option<String>,
+    repo: Option<String>,
+    key: Option<String>,
+}
+
+impl CacheFilter {
+    fn is_empty(&self) -> bool {
+        self.domain.is_none() && self.user.is_none() && self.repo.is_none() && self.key.is_none()
+    }
+
+    /// Whether the statistics stored under `key` are selected. Domains are
+    /// normalized like the badge URLs: lowercase, `.com` when no TLD is given.
+    fn matches(&self, key: &str) -> bool {
+        if let Some(exact) = &self.key {
+            return key == exact;
+        }
+        let Some((domain, user, repo)) = key_repository(key) else {
+            return false;
+        };
+        let domain_matches = self.domain.as_deref().is_none_or(|wanted| {
+            let mut wanted = wanted.to_ascii_lowercase();
+            if !wanted.contains('.') {
+                wanted.push_str(".com");
+            }
+            wanted == domain
+        });
+        domain_matches
+            && self.user.as_deref().is_none_or(|wanted| wanted == user)
+            && self.repo.as_deref().is_none_or(|wanted| wanted == repo)
+    }
+}
+
+/// Domain, user and repository of a statistics key
+/// (`https://{domain}/{user}/{repo}#{sha}#{reference}...`).
+fn key_repository(key: &str) -> Option<(&str, &str, &str)> {
+    let url = key.strip_prefix("https://")?.split('#').next()?;
+    let mut parts = url.splitn(3, '/');
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+/// Rejects the request unless it carries the configured admin token. The
+/// endpoints don't exist without one.
+fn authorize(request: &HttpRequest, data: &AppConfig) -> Result<(), HttpResponse> {
+    let Some(token) = &data.admin_token else {
+        return Err(HttpResponse::NotFound().finish());
+    };
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if constant_time_eq(provided.trim().as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        log::warn!("Rejected admin request to {}", request.path());
+        Err(HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .json(serde_json::json!({ "error": "unauthorized" })))
+    }
+}
+
+/// Compares two byte strings in a time that only depends on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(serde::Serialize)]
+struct ListedEntry {
+    key: String,
+    age_seconds: u64,
+    total: LanguageSummary,
+    languages: usize,
+}
+
+/// Lists the cached statistics, most recently used first with the memory
+/// backend, optionally narrowed like a purge.
+#[get("/admin/v1/cache")]
+pub async fn list_cache(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    web::Query(filter): web::Query<CacheFilter>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&request, &data) {
+        return Ok(response);
+    }
+    let entries: Vec<ListedEntry> = data
+        .cache
+        .entries()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .into_iter()
+        .filter(|entry| filter.matches(&entry.key))
+        .map(|entry| {
+            let mut total = Language::new();
+            for (_, language) in &entry.counts.languages {
+                total += language.clone();
+            }
+            ListedEntry {
+                key: entry.key,
+                age_seconds: entry.age.as_secs(),
+                total: LanguageSummary::from(&total),
+                languages: entry.counts.languages.len(),
+            }
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "backend": data.cache.name(),
+        "entries": entries,
+    })))
+}
+
+/// Removes the statistics of a repository, of every repository of a user or
+/// domain, or under an exact key, from the cache backend and the disk cache.
+/// At least one filter is required.
+#[delete("/admin/v1/cache")]
+pub async fn purge_cache(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    web::Query(filter): web::Query<CacheFilter>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&request, &data) {
+        return Ok(response);
+    }
+    if filter.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "one of domain, user, repo or key is required"
+        })));
+    }
+
+    let mut keys: Vec<String> = data
+        .cache
+        .entries()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .into_iter()
+        .map(|entry| entry.key)
+        .collect();
+    if let Some(disk_cache) = data.disk_cache.clone() {
+        let disk_keys = web::block(move || disk_cache.keys())
+            .await?
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        keys.extend(disk_keys);
+    }
+    keys.retain(|key| filter.matches(key));
+    keys.sort();
+    keys.dedup();
+
+    // A key counts as purged once, whichever tiers it was removed from.
+    let mut removed: Vec<bool> = Vec::with_capacity(keys.len());
+    for key in &keys {
+        removed.push(
+            data.cache
+                .remove(key)
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?,
+        );
+    }
+    if let Some(disk_cache) = data.disk_cache.clone() {
+        let disk_keys = keys.clone();
+        let removed_from_disk = web::block(move || {
+            disk_keys
+                .iter()
+                .map(|key| disk_cache.remove(key))
+                .collect::<eyre::Result<Vec<bool>>>()
+        })
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        for (removed, from_disk) in removed.iter_mut().zip(removed_from_disk) {
+            *removed |= from_disk;
+        }
+    }
+    let purged: Vec<String> = keys
+        .into_iter()
+        .zip(removed)
+        .filter_map(|(key, removed)| removed.then_some(key))
+        .collect();
+    data.cache.stats().record_purged(purged.len());
+    log::info!("Purged {} cached statistics", purged.len());
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "purged": purged.len(),
+        "keys": purged,
+    })))
+}
+
+/// Hit, miss and eviction counters of the cache since the server started.
+#[get("/admin/v1/stats")]
+pub async fn cache_stats(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&request, &data) {
+        return Ok(response);
+    }
+    let stats = data.cache.stats();
+    let entries = data
+        .cache
+        .entry_count()
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "backend": data.cache.name(),
+        "entries": entries,
+        "hits": stats.hits(),
+        "misses": stats.misses(),
+        "evictions": data.cache.tracks_evictions().then(|| stats.evictions()),
+        "purged": stats.purged(),
+        "coalesced": data.in_flight.coalesced(),
+        "counts": data.in_flight.leaders(),
+    })))
+}
+
+#[derive(serde::Deserialize)]
+pub struct WarmQuery {
+    domain: String,
+    user: String,
+    repo: String,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Counts the statistics of a repository in the background, so that its
+/// badges are served from the cache right away. Takes the same reference and
+/// count options as the badge endpoint.
+#[post("/admin/v1/warm")]
+pub async fn warm_cache(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    web::Query(query): web::Query<WarmQuery>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&request, &data) {
+        return Ok(response);
+    }
+    let WarmQuery {
+        domain,
+        user,
+        repo,
+        statistics,
+    } = query;
+    let (status, resolved, key) =
+        match warm_statistics(&data, (domain, user, repo), &statistics).await? {
+            Warming::Cached(resolved, key) => ("cached", resolved, key),
+            Warming::Started(resolved, key) => ("started", resolved, key),
+            Warming::Forbidden => {
+                return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "forbidden"
+                })))
+            }
+            Warming::Busy => {
+                return Ok(HttpResponse::ServiceUnavailable()
+                    .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+                    .json(serde_json::json!({ "error": "busy" })))
+            }
+        };
+    let ResolvedRepository {
+        url,
+        sha,
+        branch,
+        tag,
+    } = resolved;
+    let body = serde_json::json!({
+        "status": status,
+        "key": key,
+        "url": url,
+        "sha": sha,
+        "branch": branch,
+        "tag": tag,
+    });
+    Ok(match status {
+        "started" => HttpResponse::Accepted().json(body),
+        _ => HttpResponse::Ok().json(body),
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub struct BackfillQuery {
+    domain: String,
+    user: String,
+    repo: String,
+    samples: Option<usize>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Records in the history, in the background, up to `samples` commits (20
+/// by default) evenly spread along the first-parent history of the requested
+/// reference. Takes the same reference and count options as the badge
+/// endpoint.
+#[post("/admin/v1/history/backfill")]
+pub async fn backfill_history(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    web::Query(query): web::Query<BackfillQuery>,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(response) = authorize(&request, &data) {
+        return Ok(response);
+    }
+    if data.history.is_none() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "history is disabled"
+        })));
+    }
+    let samples = query.samples.unwrap_or(20).clamp(1, history::MAX_SAMPLES);
+    let requested = query.statistics.requested_ref()?;
+    let options = query.statistics.count_options(&data.tokei_defaults)?;
+    let resolved =
+        match resolve_repository(&data, &query.domain, &query.user, &query.repo, &requested).await?
+        {
+            Resolution::Resolved(resolved) => resolved,
+            Resolution::Forbidden => {
+                return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "forbidden"
+                })))
+            }
+            Resolution::Busy => {
+                return Ok(HttpResponse::ServiceUnavailable()
+                    .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+                    .json(serde_json::json!({ "error": "busy" })))
+            }
+        };
+    let body = serde_json::json!({
+        "status": "started",
+        "url": resolved.url,
+        "sha": resolved.sha,
+        "branch": resolved.branch,
+        "tag": resolved.tag,
+        "samples": samples,
+    });
+    actix_web::rt::spawn(history::backfill(data.clone(), resolved, options, samples));
+    Ok(HttpResponse::Accepted().json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        http::header::AUTHORIZATION,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App,
+    };
+    use tempfile::TempDir;
+
+    use super::purge_cache;
+    use crate::{disk_cache::DiskCache, Counts};
+
+    #[actix_web::test]
+    async fn purged_entries_are_counted_once() {
+        let dir = TempDir::new().unwrap();
+        let mut config = crate::tests::app_config();
+        config.admin_token = Some("secret".to_owned());
+        config.disk_cache = Some(Arc::new(DiskCache::open(dir.path(), 60).unwrap()));
+        let data = web::Data::new(config);
+
+        let counts = Counts {
+            languages: Vec::new(),
+            default_category: None,
+            committed_at: None,
+        };
+        let both = "https://purge.example/user/repo#1#main";
+        let disk_only = "https://purge.example/user/repo#2#main";
+        let other = "https://purge.example/user/other#1#main";
+        data.cache.set(both, &counts).await.unwrap();
+        data.cache.set(other, &counts).await.unwrap();
+        let disk_cache = data.disk_cache.as_ref().unwrap();
+        disk_cache.set(both, &counts).unwrap();
+        disk_cache.set(disk_only, &counts).unwrap();
+
+        let app = init_service(App::new().app_data(data.clone()).service(purge_cache)).await;
+        let purge = || {
+            TestRequest::delete()
+                .uri("/admin/v1/cache?domain=purge.example&repo=repo")
+                .insert_header((AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        let body: serde_json::Value = read_body_json(call_service(&app, purge()).await).await;
+        assert_eq!(body["purged"], 2);
+        assert_eq!(body["keys"], serde_json::json!([both, disk_only]));
+        assert_eq!(data.cache.stats().purged(), 2);
+        assert!(data.cache.get(other).await.unwrap().is_some());
+
+        let body: serde_json::Value = read_body_json(call_service(&app, purge()).await).await;
+        assert_eq!(body["purged"], 0);
+        assert_eq!(data.cache.stats().purged(), 2);
+        data.cache.remove(other).await.unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! JSON API endpoints (`/api/v1/...`) exposing repository statistics in a
+//! machine-readable form, as opposed to the `/b1/...` badge endpoints.
+
+use actix_web::{
+    get,
+    http::header::{
+        CacheControl, CacheDirective, ContentType, EntityTag, CACHE_CONTROL, ETAG, RETRY_AFTER,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use tokei::Language;
+
+use crate::{
+    filter_language_types, jobs, lookup_statistics, mark_stale, AppConfig, Lookup,
+    ResolvedRepository, Statistics, StatisticsQuery, BUSY_RETRY_AFTER_SECONDS,
+};
+
+#[derive(serde::Deserialize)]
+pub struct LanguagesQuery {
+    r#type: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Line counts for a single language (or for the whole repository).
+#[derive(serde::Serialize)]
+pub struct LanguageSummary {
+    code: usize,
+    comments: usize,
+    blanks: usize,
+    lines: usize,
+    files: usize,
+}
+
+impl From<&Language> for LanguageSummary {
+    fn from(language: &Language) -> Self {
+        LanguageSummary {
+            code: language.code,
+            comments: language.comments,
+            blanks: language.blanks,
+            lines: language.lines(),
+            files: language.reports.len(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct LanguageShare {
+    language: &'static str,
+    #[serde(flatten)]
+    summary: LanguageSummary,
+    /// Share of the repository's lines of code, in percent.
+    percentage: f64,
+    /// Part of `summary` found embedded in other languages' files, with
+    /// `embedded=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedded: Option<LanguageSummary>,
+}
+
+#[derive(serde::Serialize)]
+struct LanguageBreakdown {
+    url: String,
+    sha: String,
+    branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Statistics of the commit previously counted for the branch, while its
+    /// new head is being counted.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stale: bool,
+    total: LanguageSummary,
+    languages: Vec<LanguageShare>,
+}
+
+/// Returns every language found in the repository, most common first, along
+/// with its share of the total lines of code and the commit it was computed
+/// from.
+#[get("/api/v1/{domain}/{user}/{repo}/languages")]
+pub async fn language_breakdown(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<LanguagesQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Statistics {
+        resolved,
+        options,
+        etag,
+        languages,
+        embedded,
+        stale,
+        ..
+    } = match lookup_statistics(&request, &data, path.into_inner(), &query.statistics).await? {
+        Lookup::Found(statistics) => *statistics,
+        Lookup::NotModified => return Ok(HttpResponse::NotModified().finish()),
+        Lookup::Forbidden => {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "forbidden"
+            })))
+        }
+        Lookup::Busy => {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+                .json(serde_json::json!({
+                    "error": "busy"
+                })))
+        }
+        Lookup::Pending(id) => return Ok(jobs::accepted(&data, &id)),
+    };
+    let ResolvedRepository {
+        url,
+        sha,
+        branch,
+        tag,
+    } = resolved;
+
+    let languages = filter_language_types(languages, &query.r#type.unwrap_or_default());
+
+    let mut total = Language::new();
+    for (_, language) in &languages {
+        total += language.clone();
+    }
+
+    let breakdown = LanguageBreakdown {
+        languages: languages
+            .iter()
+            .map(|(language_type, language)| LanguageShare {
+                language: language_type.name(),
+                summary: LanguageSummary::from(language),
+                percentage: percentage(language.code, total.code),
+                embedded: embedded.get(language_type).map(LanguageSummary::from),
+            })
+            .collect(),
+        total: LanguageSummary::from(&total),
+        url,
+        sha,
+        branch,
+        tag,
+        path: options.path,
+        stale,
+    };
+
+    let mut response = HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((ETAG, EntityTag::new(false, etag)))
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&breakdown)?);
+    if stale {
+        mark_stale(&mut response);
+    }
+    Ok(response)
+}
+
+/// Returns `part` as a percentage of `total`, rounded to two decimals.
+fn percentage(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    ((part as f64) * 10_000.0 / (total as f64)).round() / 100.0
+}
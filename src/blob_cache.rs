@@ -0,0 +1,165 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Counts of single files, keyed by the object ID of their blob, so that
+//! counting a new commit only parses the files that changed since a commit
+//! counted before: the counts of the others are reused. A blob never changes,
+//! so entries don't expire; the least recently used ones make room for new
+//! ones beyond `--blob-cache-size`.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use cached::Cached;
+use git2::Oid;
+use tokei::{CodeStats, LanguageType};
+
+/// What a blob's count depends on besides its contents.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct BlobKey {
+    id: Oid,
+    language: LanguageType,
+    /// The only tokei option changing how a file is parsed.
+    doc_strings_as_comments: bool,
+}
+
+impl BlobKey {
+    pub fn new(id: Oid, language: LanguageType, config: &tokei::Config) -> Self {
+        BlobKey {
+            id,
+            language,
+            doc_strings_as_comments: config.treat_doc_strings_as_comments.unwrap_or(false),
+        }
+    }
+}
+
+pub struct BlobCache {
+    /// `None` when disabled with a size of 0.
+    entries: Option<Mutex<cached::SizedCache<BlobKey, CodeStats>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlobCache {
+    pub fn new(max_entries: usize) -> Self {
+        BlobCache {
+            entries: (max_entries > 0)
+                .then(|| Mutex::new(cached::SizedCache::with_size(max_entries))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &BlobKey) -> Option<CodeStats> {
+        let entries = self.entries.as_ref()?;
+        let stats = entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .cache_get(key)
+            .cloned();
+        let counter = if stats.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        stats
+    }
+
+    pub fn insert(&self, key: BlobKey, stats: &CodeStats) {
+        if let Some(entries) = &self.entries {
+            entries
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .cache_set(key, stats.clone());
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    /// Files whose count was reused.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Files parsed because their count wasn't cached.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+    use tokei::{CodeStats, LanguageType};
+
+    use super::{BlobCache, BlobKey};
+
+    fn config(doc_strings_as_comments: bool) -> tokei::Config {
+        tokei::Config {
+            treat_doc_strings_as_comments: Some(doc_strings_as_comments),
+            ..tokei::Config::default()
+        }
+    }
+
+    #[test]
+    fn counts_are_keyed_by_blob_language_and_doc_strings() {
+        let cache = BlobCache::new(16);
+        let id = Oid::from_str(&"1".repeat(40)).unwrap();
+        let mut stats = CodeStats::new();
+        stats.code = 7;
+        cache.insert(
+            BlobKey::new(id, LanguageType::Python, &config(false)),
+            &stats,
+        );
+
+        let hit = cache.get(&BlobKey::new(id, LanguageType::Python, &config(false)));
+        assert_eq!(hit.map(|stats| stats.code), Some(7));
+        // An unset option is its default.
+        let default = BlobKey::new(id, LanguageType::Python, &tokei::Config::default());
+        assert!(cache.get(&default).is_some());
+
+        let other_id = Oid::from_str(&"2".repeat(40)).unwrap();
+        for key in [
+            BlobKey::new(id, LanguageType::Python, &config(true)),
+            BlobKey::new(id, LanguageType::Ruby, &config(false)),
+            BlobKey::new(other_id, LanguageType::Python, &config(false)),
+        ] {
+            assert!(cache.get(&key).is_none());
+        }
+        assert_eq!((cache.hits(), cache.misses()), (2, 3));
+    }
+
+    #[test]
+    fn disabled_cache_never_hits() {
+        let cache = BlobCache::new(0);
+        let key = BlobKey::new(Oid::zero(), LanguageType::Rust, &config(false));
+        cache.insert(key.clone(), &CodeStats::new());
+        assert!(!cache.is_enabled());
+        assert!(cache.get(&key).is_none());
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+    }
+}
@@ -0,0 +1,521 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Storage shared by the statistics endpoints (`--cache-backend`).
+//!
+//! `memory` keeps the statistics in this process, in `CACHE`. `redis` keeps
+//! them in a Redis server (or anything speaking its protocol) so that every
+//! replica of a deployment serves the statistics counted by the others, and so
+//! that a commit requested from several replicas at once is only counted by
+//! one of them.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use cached::{Cached, CloneCached, Return};
+use redis::aio::ConnectionManager;
+
+use crate::{Counts, CACHE, LATEST};
+
+/// Prefix of every key written to Redis.
+const REDIS_PREFIX: &str = "tokeisrv";
+/// How long a replica may hold the lock of a commit it counts, in case it
+/// dies before releasing it.
+const COUNT_LOCK_MILLISECONDS: u64 = 10 * 60 * 1000;
+
+/// Releases a count lock only if this process still holds it.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Statistics stored under a `statistics_key`, as listed by `entries`.
+pub struct CacheEntry {
+    pub key: String,
+    /// Time since the statistics were stored.
+    pub age: Duration,
+    pub counts: Counts,
+}
+
+/// Counters of a cache backend, reported by the admin API and `/metrics`.
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    purged: AtomicU64,
+}
+
+impl CacheStats {
+    /// Records a request served from the cache.
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request whose statistics weren't cached.
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records entries removed through the admin API.
+    pub fn record_purged(&self, count: usize) {
+        self.purged.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn record_eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Entries dropped because they expired or to make room for new ones.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    pub fn purged(&self) -> u64 {
+        self.purged.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+pub trait StatisticsCache: Send + Sync {
+    /// Name of the backend, as given to `--cache-backend`.
+    fn name(&self) -> &'static str;
+
+    fn stats(&self) -> &CacheStats;
+
+    /// Whether `stats().evictions()` is counted by this backend.
+    fn tracks_evictions(&self) -> bool {
+        true
+    }
+
+    /// Statistics stored under a `statistics_key`.
+    async fn get(&self, key: &str) -> eyre::Result<Option<Counts>>;
+
+    async fn set(&self, key: &str, counts: &Counts) -> eyre::Result<()>;
+
+    /// Marks the statistics under `key` as recently used, without reading
+    /// them.
+    async fn touch(&self, _key: &str) {}
+
+    /// The commit last counted for a `latest_key`.
+    async fn latest(&self, latest_key: &str) -> eyre::Result<Option<String>>;
+
+    async fn set_latest(&self, latest_key: &str, sha: &str) -> eyre::Result<()>;
+
+    /// Tries to become the only one counting the statistics of `key`,
+    /// `false` when someone else already is. Requests of a single process
+    /// are coalesced before getting here.
+    async fn try_lock(&self, key: &str) -> eyre::Result<bool>;
+
+    async fn unlock(&self, key: &str) -> eyre::Result<()>;
+
+    /// Every unexpired entry, for the admin API.
+    async fn entries(&self) -> eyre::Result<Vec<CacheEntry>>;
+
+    /// Number of unexpired entries.
+    async fn entry_count(&self) -> eyre::Result<usize>;
+
+    /// Removes the statistics under `key`, `true` if there were any.
+    async fn remove(&self, key: &str) -> eyre::Result<bool>;
+}
+
+/// Statistics kept in this process only.
+#[derive(Default)]
+pub struct MemoryCache {
+    stats: CacheStats,
+}
+
+#[async_trait]
+impl StatisticsCache for MemoryCache {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    async fn get(&self, key: &str) -> eyre::Result<Option<Counts>> {
+        let (entry, expired) = CACHE.lock().unwrap().cache_get_expired(key);
+        if expired {
+            self.stats.record_eviction();
+        }
+        Ok(entry.map(|entry| entry.value))
+    }
+
+    async fn set(&self, key: &str, counts: &Counts) -> eyre::Result<()> {
+        let mut cache = CACHE.lock().unwrap();
+        let size = cache.cache_size();
+        let replaced = cache.cache_set(key.to_owned(), Return::new(counts.clone()));
+        // A new key that didn't grow the cache took the place of another one.
+        if replaced.is_none() && size > 0 && cache.cache_size() == size {
+            self.stats.record_eviction();
+        }
+        Ok(())
+    }
+
+    async fn touch(&self, key: &str) {
+        CACHE.lock().unwrap().cache_get(key);
+    }
+
+    async fn latest(&self, latest_key: &str) -> eyre::Result<Option<String>> {
+        Ok(LATEST.lock().unwrap().cache_get(latest_key).cloned())
+    }
+
+    async fn set_latest(&self, latest_key: &str, sha: &str) -> eyre::Result<()> {
+        LATEST
+            .lock()
+            .unwrap()
+            .cache_set(latest_key.to_owned(), sha.to_owned());
+        Ok(())
+    }
+
+    async fn try_lock(&self, _key: &str) -> eyre::Result<bool> {
+        Ok(true)
+    }
+
+    async fn unlock(&self, _key: &str) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    async fn entries(&self) -> eyre::Result<Vec<CacheEntry>> {
+        let cache = CACHE.lock().unwrap();
+        let lifespan = cache.cache_lifespan();
+        Ok(cache
+            .key_order()
+            .zip(cache.value_order())
+            .filter(|(_, (stored, _))| lifespan.is_none_or(|lifespan| stored.elapsed() < lifespan))
+            .map(|(key, (stored, entry))| CacheEntry {
+                key: key.clone(),
+                age: stored.elapsed(),
+                counts: entry.value.clone(),
+            })
+            .collect())
+    }
+
+    async fn entry_count(&self) -> eyre::Result<usize> {
+        let cache = CACHE.lock().unwrap();
+        let lifespan = cache.cache_lifespan();
+        Ok(cache
+            .value_order()
+            .filter(|(stored, _)| lifespan.is_none_or(|lifespan| stored.elapsed() < lifespan))
+            .count())
+    }
+
+    async fn remove(&self, key: &str) -> eyre::Result<bool> {
+        Ok(CACHE.lock().unwrap().cache_remove(key).is_some())
+    }
+}
+
+/// Statistics shared through a Redis server. Entries expire after the cache
+/// TTL; evictions are left to the server's `maxmemory-policy`.
+pub struct RedisCache {
+    connection: ConnectionManager,
+    stats: CacheStats,
+    ttl_seconds: u64,
+    /// Identifies the count locks held by this process.
+    lock_token: String,
+}
+
+impl RedisCache {
+    /// Connects to the server at `url`, e.g. `redis://127.0.0.1:6379/0`.
+    pub async fn connect(url: &str, ttl_seconds: u64) -> eyre::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = client.get_connection_manager().await?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Ok(RedisCache {
+            connection,
+            stats: CacheStats::default(),
+            ttl_seconds: ttl_seconds.max(1),
+            lock_token: format!("{}-{}", std::process::id(), nanos),
+        })
+    }
+
+    fn statistics_key(key: &str) -> String {
+        format!("{}:statistics:{}", REDIS_PREFIX, key)
+    }
+
+    fn latest_key(key: &str) -> String {
+        format!("{}:latest:{}", REDIS_PREFIX, key)
+    }
+
+    fn lock_key(key: &str) -> String {
+        format!("{}:lock:{}", REDIS_PREFIX, key)
+    }
+
+    /// Redis keys of every stored statistics entry.
+    async fn statistics_keys(&self) -> eyre::Result<Vec<String>> {
+        let mut connection = self.connection.clone();
+        let pattern = format!("{}:statistics:*", REDIS_PREFIX);
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(100)
+                .query_async(&mut connection)
+                .await?;
+            keys.extend(batch);
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+}
+
+#[async_trait]
+impl StatisticsCache for RedisCache {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Expired and evicted keys are dropped by the server on its own.
+    fn tracks_evictions(&self) -> bool {
+        false
+    }
+
+    async fn get(&self, key: &str) -> eyre::Result<Option<Counts>> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(Self::statistics_key(key))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+
+    async fn set(&self, key: &str, counts: &Counts) -> eyre::Result<()> {
+        redis::cmd("SET")
+            .arg(Self::statistics_key(key))
+            .arg(serde_json::to_string(counts)?)
+            .arg("EX")
+            .arg(self.ttl_seconds)
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn latest(&self, latest_key: &str) -> eyre::Result<Option<String>> {
+        Ok(redis::cmd("GET")
+            .arg(Self::latest_key(latest_key))
+            .query_async(&mut self.connection.clone())
+            .await?)
+    }
+
+    async fn set_latest(&self, latest_key: &str, sha: &str) -> eyre::Result<()> {
+        redis::cmd("SET")
+            .arg(Self::latest_key(latest_key))
+            .arg(sha)
+            .arg("EX")
+            .arg(self.ttl_seconds)
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn try_lock(&self, key: &str) -> eyre::Result<bool> {
+        let locked: Option<String> = redis::cmd("SET")
+            .arg(Self::lock_key(key))
+            .arg(&self.lock_token)
+            .arg("NX")
+            .arg("PX")
+            .arg(COUNT_LOCK_MILLISECONDS)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(locked.is_some())
+    }
+
+    async fn unlock(&self, key: &str) -> eyre::Result<()> {
+        redis::Script::new(UNLOCK_SCRIPT)
+            .key(Self::lock_key(key))
+            .arg(&self.lock_token)
+            .invoke_async::<i64>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn entries(&self) -> eyre::Result<Vec<CacheEntry>> {
+        let prefix = Self::statistics_key("");
+        let mut connection = self.connection.clone();
+        let mut entries = Vec::new();
+        for redis_key in self.statistics_keys().await? {
+            let (value, ttl): (Option<String>, i64) = redis::pipe()
+                .cmd("GET")
+                .arg(&redis_key)
+                .cmd("TTL")
+                .arg(&redis_key)
+                .query_async(&mut connection)
+                .await?;
+            // Expired between the scan and now.
+            let Some(value) = value else {
+                continue;
+            };
+            let remaining = u64::try_from(ttl).unwrap_or(0);
+            entries.push(CacheEntry {
+                key: redis_key[prefix.len()..].to_owned(),
+                age: Duration::from_secs(self.ttl_seconds.saturating_sub(remaining)),
+                counts: serde_json::from_str(&value)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn entry_count(&self) -> eyre::Result<usize> {
+        Ok(self.statistics_keys().await?.len())
+    }
+
+    async fn remove(&self, key: &str) -> eyre::Result<bool> {
+        let removed: u64 = redis::cmd("DEL")
+            .arg(Self::statistics_key(key))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryCache, RedisCache, StatisticsCache};
+    use crate::Counts;
+
+    /// Redis server the `RedisCache` tests run against, e.g.
+    /// `TOKEISRV_TEST_REDIS=redis://127.0.0.1:6379/15`. They are skipped when
+    /// it isn't set.
+    const TEST_REDIS: &str = "TOKEISRV_TEST_REDIS";
+
+    fn counts(committed_at: i64) -> Counts {
+        Counts {
+            languages: Vec::new(),
+            default_category: Some("comments".to_owned()),
+            committed_at: Some(committed_at),
+        }
+    }
+
+    /// Keys no other test (or earlier run against the same server) uses.
+    fn unique(name: &str) -> String {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        format!(
+            "https://example.com/{}/{}-{}",
+            name,
+            std::process::id(),
+            nanos
+        )
+    }
+
+    async fn redis() -> Option<RedisCache> {
+        let url = std::env::var(TEST_REDIS).ok()?;
+        Some(RedisCache::connect(&url, 60).await.unwrap())
+    }
+
+    /// Behaviour both backends share.
+    async fn assert_stores_statistics(cache: &dyn StatisticsCache) {
+        let key = unique("statistics");
+        assert!(cache.get(&key).await.unwrap().is_none());
+        cache.set(&key, &counts(1)).await.unwrap();
+        cache.set(&key, &counts(2)).await.unwrap();
+        let stored = cache.get(&key).await.unwrap().unwrap();
+        assert_eq!(stored.committed_at, Some(2));
+        assert_eq!(stored.default_category.as_deref(), Some("comments"));
+
+        let entries = cache.entries().await.unwrap();
+        let entry = entries.iter().find(|entry| entry.key == key).unwrap();
+        assert_eq!(entry.counts.committed_at, Some(2));
+        assert!(cache.entry_count().await.unwrap() >= 1);
+
+        assert!(cache.remove(&key).await.unwrap());
+        assert!(!cache.remove(&key).await.unwrap());
+        assert!(cache.get(&key).await.unwrap().is_none());
+
+        let latest_key = unique("latest");
+        assert!(cache.latest(&latest_key).await.unwrap().is_none());
+        cache.set_latest(&latest_key, "a").await.unwrap();
+        cache.set_latest(&latest_key, "b").await.unwrap();
+        assert_eq!(
+            cache.latest(&latest_key).await.unwrap().as_deref(),
+            Some("b")
+        );
+    }
+
+    #[actix_web::test]
+    async fn memory_cache_stores_statistics() {
+        let cache = MemoryCache::default();
+        assert_stores_statistics(&cache).await;
+        // Requests of a single process are coalesced before locking.
+        let key = unique("lock");
+        assert!(cache.try_lock(&key).await.unwrap());
+        assert!(cache.try_lock(&key).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn redis_cache_stores_statistics() {
+        let Some(cache) = redis().await else {
+            return;
+        };
+        assert_stores_statistics(&cache).await;
+    }
+
+    #[actix_web::test]
+    async fn redis_count_lock_is_exclusive() {
+        let (Some(first), Some(second)) = (redis().await, redis().await) else {
+            return;
+        };
+        assert_ne!(first.lock_token, second.lock_token);
+        let key = unique("lock");
+        assert!(first.try_lock(&key).await.unwrap());
+        assert!(!first.try_lock(&key).await.unwrap());
+        assert!(!second.try_lock(&key).await.unwrap());
+        // Only the holder releases the lock.
+        second.unlock(&key).await.unwrap();
+        assert!(!second.try_lock(&key).await.unwrap());
+        first.unlock(&key).await.unwrap();
+        assert!(second.try_lock(&key).await.unwrap());
+        second.unlock(&key).await.unwrap();
+    }
+}
@@ -0,0 +1,506 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! SVG charts of a repository's language distribution, complementing the
+//! two-part badges produced by `make_badge_style`.
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective, EntityTag, CACHE_CONTROL, CONTENT_TYPE, ETAG},
+    web, HttpRequest, HttpResponse,
+};
+use tokei::{Language, LanguageType};
+
+use crate::{
+    busy_response, computing_response, filter_language_types, format_amount, lookup_statistics,
+    make_badge_style, mark_stale, AppConfig, Lookup, Statistics, StatisticsQuery, BLANKS, CODE,
+    COMMENTS, CONTENT_TYPE_SVG, FILES, LINES,
+};
+
+const FONT_FAMILY: &str = "-apple-system,BlinkMacSystemFont,Segoe UI,Helvetica,Arial,sans-serif";
+const OTHER: &str = "Other";
+const OTHER_COLOR: &str = "#ededed";
+const TEXT_COLOR: &str = "#333333";
+/// Colors used when a language has no entry in `language_color`.
+const PALETTE: [&str; 10] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac",
+];
+
+/// One segment of a chart: a language (or the "Other" bucket) and its value in
+/// the requested category.
+pub struct Slice {
+    pub label: String,
+    pub value: usize,
+    pub color: String,
+}
+
+/// Returns the color GitHub's linguist uses for `language_type`, or a stable
+/// palette color for languages linguist doesn't know about.
+pub fn language_color(language_type: LanguageType) -> String {
+    use LanguageType::*;
+    let color = match language_type {
+        Assembly | AssemblyGAS => "#6e4c13",
+        Bash | Sh | Zsh | Fish | CShell => "#89e051",
+        C => "#555555",
+        CHeader | CppHeader => "#a8b9cc",
+        CSharp => "#178600",
+        Clojure | ClojureC | ClojureScript => "#db5855",
+        CMake => "#da3434",
+        CoffeeScript => "#244776",
+        Cpp => "#f34b7d",
+        Css => "#563d7c",
+        Dart => "#00b4ab",
+        Dockerfile => "#384d54",
+        Elixir => "#6e4a7e",
+        Elm => "#60b5cc",
+        Erlang => "#b83998",
+        FSharp => "#b845fc",
+        Go => "#00add8",
+        Groovy => "#4298b8",
+        Haskell => "#5e5086",
+        Html => "#e34c26",
+        Java => "#b07219",
+        JavaScript | Jsx => "#f1e05a",
+        Json => "#292929",
+        Julia => "#a270ba",
+        Kotlin => "#a97bff",
+        Less => "#1d365d",
+        Lua => "#000080",
+        Makefile => "#427819",
+        Markdown => "#083fa1",
+        Nix => "#7e7eff",
+        OCaml => "#3be133",
+        ObjectiveC => "#438eff",
+        ObjectiveCpp => "#6866fb",
+        Perl => "#0298c3",
+        Php => "#4f5d95",
+        PowerShell => "#012456",
+        Python => "#3572a5",
+        R => "#198ce7",
+        Ruby => "#701516",
+        Rust => "#dea584",
+        Sass => "#a53b70",
+        Scala => "#c22d40",
+        Sql => "#e38c00",
+        Svelte => "#ff3e00",
+        Swift => "#f05138",
+        Tex => "#3d6117",
+        Toml => "#9c4221",
+        Tsx | TypeScript => "#3178c6",
+        VimScript => "#199f4b",
+        Vue => "#41b883",
+        Xml => "#0060ac",
+        Yaml => "#cb171e",
+        Zig => "#ec915c",
+        _ => {
+            let hash = language_type.name().bytes().fold(0usize, |hash, byte| {
+                hash.wrapping_mul(31).wrapping_add(byte as usize)
+            });
+            PALETTE[hash % PALETTE.len()]
+        }
+    };
+    color.to_owned()
+}
+
+/// Value of `language` in one of the badge categories (`code` when unknown).
+pub fn category_value(language: &Language, category: &str) -> usize {
+    match category {
+        "lines" => language.lines(),
+        "files" => language.reports.len(),
+        "blanks" => language.blanks,
+        "comments" => language.comments,
+        _ => language.code,
+    }
+}
+
+/// Turns the language vector into chart slices sorted by `category`, keeping
+/// the `top` largest languages and summing the rest into an "Other" slice.
+/// Languages with nothing to show in that category are left out.
+pub fn language_slices(
+    languages: &[(LanguageType, Language)],
+    category: &str,
+    top: usize,
+) -> Vec<Slice> {
+    let mut values: Vec<(LanguageType, usize)> = languages
+        .iter()
+        .map(|(language_type, language)| (*language_type, category_value(language, category)))
+        .filter(|(_, value)| *value > 0)
+        .collect();
+    values.sort_by_key(|(_, value)| std::cmp::Reverse(*value));
+
+    let top = top.max(1);
+    let mut slices: Vec<Slice> = values
+        .iter()
+        .take(top)
+        .map(|(language_type, value)| Slice {
+            label: language_type.name().to_owned(),
+            value: *value,
+            color: language_color(*language_type),
+        })
+        .collect();
+    let other: usize = values.iter().skip(top).map(|(_, value)| value).sum();
+    if other > 0 {
+        slices.push(Slice {
+            label: OTHER.to_owned(),
+            value: other,
+            color: OTHER_COLOR.to_owned(),
+        });
+    }
+    slices
+}
+
+/// Escapes text for inclusion in SVG markup.
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders a legend of `slices` as rows of colored dots followed by the
+/// language name and its share, starting at `(x, y)`. Returns the markup and
+/// the height it occupies.
+pub fn render_legend(slices: &[Slice], x: f64, y: f64, width: f64) -> (String, f64) {
+    const ROW_HEIGHT: f64 = 20.0;
+    const COLUMN_WIDTH: f64 = 140.0;
+
+    let total: usize = slices.iter().map(|slice| slice.value).sum();
+    let columns = ((width / COLUMN_WIDTH).floor() as usize).max(1);
+    let mut svg = String::new();
+    for (index, slice) in slices.iter().enumerate() {
+        let item_x = x + (index % columns) as f64 * COLUMN_WIDTH;
+        let item_y = y + (index / columns) as f64 * ROW_HEIGHT + ROW_HEIGHT / 2.0;
+        svg.push_str(&format!(
+            r#"<circle cx="{cx:.1}" cy="{cy:.1}" r="5" fill="{color}"/><text x="{tx:.1}" y="{ty:.1}" fill="{text}">{label} <tspan fill-opacity="0.7">{share:.1}%</tspan></text>"#,
+            cx = item_x + 5.0,
+            cy = item_y,
+            color = slice.color,
+            tx = item_x + 14.0,
+            ty = item_y + 4.0,
+            text = TEXT_COLOR,
+            label = escape_xml(&slice.label),
+            share = share(slice.value, total) * 100.0,
+        ));
+    }
+    let rows = slices.len().div_ceil(columns);
+    (svg, rows as f64 * ROW_HEIGHT)
+}
+
+/// Fraction of `total` represented by `value`, in `[0, 1]`.
+pub fn share(value: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        value as f64 / total as f64
+    }
+}
+
+/// Renders a horizontal stacked bar of `slices` with a legend underneath, in
+/// the spirit of the language bar shown on GitHub repository pages.
+pub fn render_bar(slices: &[Slice], width: usize) -> String {
+    const PADDING: f64 = 10.0;
+    const BAR_HEIGHT: f64 = 8.0;
+
+    let width = width as f64;
+    let inner_width = width - 2.0 * PADDING;
+    let total: usize = slices.iter().map(|slice| slice.value).sum();
+
+    let mut segments = String::new();
+    let mut offset = PADDING;
+    for slice in slices {
+        let segment_width = share(slice.value, total) * inner_width;
+        segments.push_str(&format!(
+            r#"<rect x="{x:.2}" y="{y}" width="{w:.2}" height="{h}" fill="{color}"><title>{label}</title></rect>"#,
+            x = offset,
+            y = PADDING,
+            w = segment_width,
+            h = BAR_HEIGHT,
+            color = slice.color,
+            label = escape_xml(&slice.label),
+        ));
+        offset += segment_width;
+    }
+    if slices.is_empty() {
+        segments.push_str(&format!(
+            r#"<rect x="{x}" y="{y}" width="{w:.2}" height="{h}" fill="{color}"/>"#,
+            x = PADDING,
+            y = PADDING,
+            w = inner_width,
+            h = BAR_HEIGHT,
+            color = OTHER_COLOR,
+        ));
+    }
+
+    let legend_y = PADDING + BAR_HEIGHT + 8.0;
+    let (legend, legend_height) = render_legend(slices, PADDING, legend_y, inner_width);
+    let height = legend_y + legend_height + PADDING;
+
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="{font}" font-size="11"><clipPath id="bar"><rect x="{padding}" y="{padding}" width="{inner_width:.2}" height="{bar_height}" rx="4"/></clipPath><g clip-path="url(#bar)">{segments}</g>{legend}</svg>"#,
+        width = width,
+        height = height,
+        font = FONT_FAMILY,
+        padding = PADDING,
+        inner_width = inner_width,
+        bar_height = BAR_HEIGHT,
+        segments = segments,
+        legend = legend,
+    )
+}
+
+/// Renders a donut chart of `slices` of the given diameter with the total in
+/// its center and a legend on its right.
+pub fn render_donut(slices: &[Slice], category: &str, size: usize) -> String {
+    const PADDING: f64 = 10.0;
+    const LEGEND_WIDTH: f64 = 140.0;
+
+    let size = size as f64;
+    let outer_radius = size / 2.0;
+    let thickness = outer_radius * 0.4;
+    // Each slice is drawn as a dashed stroke along the middle of the ring,
+    // which handles a single slice covering the whole circle gracefully.
+    let radius = outer_radius - thickness / 2.0;
+    let circumference = 2.0 * std::f64::consts::PI * radius;
+    let (center_x, center_y) = (PADDING + outer_radius, PADDING + outer_radius);
+    let total: usize = slices.iter().map(|slice| slice.value).sum();
+
+    let mut ring = format!(
+        r#"<circle cx="{cx:.2}" cy="{cy:.2}" r="{r:.2}" fill="none" stroke="{color}" stroke-width="{w:.2}"/>"#,
+        cx = center_x,
+        cy = center_y,
+        r = radius,
+        color = OTHER_COLOR,
+        w = thickness,
+    );
+    let mut offset = 0.0;
+    for slice in slices {
+        let length = share(slice.value, total) * circumference;
+        ring.push_str(&format!(
+            r#"<circle cx="{cx:.2}" cy="{cy:.2}" r="{r:.2}" fill="none" stroke="{color}" stroke-width="{w:.2}" stroke-dasharray="{len:.3} {gap:.3}" stroke-dashoffset="{offset:.3}" transform="rotate(-90 {cx:.2} {cy:.2})"><title>{label}</title></circle>"#,
+            cx = center_x,
+            cy = center_y,
+            r = radius,
+            color = slice.color,
+            w = thickness,
+            len = length,
+            gap = circumference - length,
+            offset = 0.0 - offset,
+            label = escape_xml(&slice.label),
+        ));
+        offset += length;
+    }
+
+    let category_label = match category {
+        "lines" => LINES,
+        "files" => FILES,
+        "blanks" => BLANKS,
+        "comments" => COMMENTS,
+        _ => CODE,
+    };
+    let center = format!(
+        r#"<text x="{cx:.2}" y="{cy:.2}" text-anchor="middle" font-size="{big:.1}" font-weight="bold" fill="{text}">{amount}</text><text x="{cx:.2}" y="{cy2:.2}" text-anchor="middle" fill="{text}" fill-opacity="0.7">{label}</text>"#,
+        cx = center_x,
+        cy = center_y + 2.0,
+        cy2 = center_y + 16.0,
+        big = (size / 8.0).clamp(11.0, 32.0),
+        text = TEXT_COLOR,
+        amount = format_amount(total),
+        label = category_label,
+    );
+
+    let (legend, legend_height) =
+        render_legend(slices, size + 2.0 * PADDING, PADDING, LEGEND_WIDTH);
+    let width = size + 3.0 * PADDING + LEGEND_WIDTH;
+    let height = size.max(legend_height) + 2.0 * PADDING;
+
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="{font}" font-size="11">{ring}{center}{legend}</svg>"#,
+        width = width,
+        height = height,
+        font = FONT_FAMILY,
+        ring = ring,
+        center = center,
+        legend = legend,
+    )
+}
+
+/// Applies `colors` overrides given as comma-separated `Language:color` pairs,
+/// e.g. `Rust:#dea584,Python:orange`. Unknown languages and unparsable colors
+/// are ignored.
+pub fn apply_color_overrides(slices: &mut [Slice], colors: &str) {
+    for pair in colors.split(',') {
+        let Some((language, color)) = pair.split_once(':') else {
+            continue;
+        };
+        let Ok(color) = csscolorparser::parse(color.trim()) else {
+            continue;
+        };
+        for slice in slices.iter_mut() {
+            if slice.label.eq_ignore_ascii_case(language.trim()) {
+                slice.color = color.to_css_hex();
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct DonutQuery {
+    category: Option<String>,
+    r#type: Option<String>,
+    top: Option<String>,
+    size: Option<String>,
+    colors: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+#[derive(serde::Deserialize)]
+pub struct BarQuery {
+    category: Option<String>,
+    r#type: Option<String>,
+    top: Option<String>,
+    width: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Parses an optional numeric query parameter, falling back to `default` when
+/// absent or invalid and clamping the result to `min..=max`.
+pub fn parse_bounded(value: Option<String>, default: usize, min: usize, max: usize) -> usize {
+    value
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(default)
+        .clamp(min, max)
+}
+
+/// Builds the SVG response shared by the chart endpoints.
+pub fn svg_response(svg: String, etag: String, stale: bool) -> HttpResponse {
+    let mut response = HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((ETAG, EntityTag::new(false, etag)))
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(svg);
+    if stale {
+        mark_stale(&mut response);
+    }
+    response
+}
+
+/// Builds the red 'forbidden' badge returned when a whitelist rejects a chart
+/// request, mirroring the badge endpoint.
+pub async fn forbidden_response() -> actix_web::Result<HttpResponse> {
+    let badge = make_badge_style("", "forbidden", "#e05d44", "plastic", "").await?;
+    Ok(HttpResponse::Forbidden()
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(badge))
+}
+
+/// The category a chart is drawn by: the requested one, else the one set by
+/// the repository's `.tokeisrv.toml`, else lines of code.
+fn chart_category(requested: Option<String>, default_category: Option<String>) -> String {
+    requested
+        .or(default_category)
+        .unwrap_or_else(|| "code".to_owned())
+}
+
+/// Horizontal bar of the top languages by lines of code (or by `category`).
+#[get("/b1/{domain}/{user}/{repo}/languages.svg")]
+pub async fn language_bar(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<BarQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let top = parse_bounded(query.top, 6, 1, 20);
+    let width = parse_bounded(query.width, 300, 120, 1200);
+
+    let Statistics {
+        etag,
+        languages,
+        default_category,
+        stale,
+        ..
+    } = match lookup_statistics(&request, &data, path.into_inner(), &query.statistics).await? {
+        Lookup::Found(statistics) => *statistics,
+        Lookup::NotModified => return Ok(HttpResponse::NotModified().finish()),
+        Lookup::Forbidden => return forbidden_response().await,
+        Lookup::Busy => return busy_response().await,
+        Lookup::Pending(id) => return computing_response(&id).await,
+    };
+
+    let category = chart_category(query.category, default_category);
+    let languages = filter_language_types(languages, &query.r#type.unwrap_or_default());
+    let slices = language_slices(&languages, &category, top);
+
+    Ok(svg_response(render_bar(&slices, width), etag, stale))
+}
+
+/// Donut chart of the language shares by lines of code (or by `category`).
+#[get("/b1/{domain}/{user}/{repo}/donut.svg")]
+pub async fn language_donut(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<DonutQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let top = parse_bounded(query.top, 5, 1, 20);
+    let size = parse_bounded(query.size, 160, 80, 600);
+
+    let Statistics {
+        etag,
+        languages,
+        default_category,
+        stale,
+        ..
+    } = match lookup_statistics(&request, &data, path.into_inner(), &query.statistics).await? {
+        Lookup::Found(statistics) => *statistics,
+        Lookup::NotModified => return Ok(HttpResponse::NotModified().finish()),
+        Lookup::Forbidden => return forbidden_response().await,
+        Lookup::Busy => return busy_response().await,
+        Lookup::Pending(id) => return computing_response(&id).await,
+    };
+
+    let category = chart_category(query.category, default_category);
+    let languages = filter_language_types(languages, &query.r#type.unwrap_or_default());
+    let mut slices = language_slices(&languages, &category, top);
+    apply_color_overrides(&mut slices, &query.colors.unwrap_or_default());
+
+    Ok(svg_response(
+        render_donut(&slices, &category, size),
+        etag,
+        stale,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::chart_category;
+
+    #[test]
+    fn requested_category_wins_over_the_repository_one() {
+        let some = |category: &str| Some(category.to_owned());
+        assert_eq!(chart_category(None, None), "code");
+        assert_eq!(chart_category(None, some("comments")), "comments");
+        assert_eq!(chart_category(some("files"), some("comments")), "files");
+    }
+}
@@ -0,0 +1,613 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Per-language difference between the statistics of two commits of a
+//! repository, e.g. to show what a pull request adds with a `+1.2K` badge.
+//! `base` and `head` are branch names, tag names or commit SHAs; both commits
+//! are counted like any other, sharing the cache of their badges.
+
+use actix_web::{
+    get,
+    http::header::{
+        CacheControl, CacheDirective, ContentType, EntityTag, CACHE_CONTROL, CONTENT_TYPE, ETAG,
+        RETRY_AFTER,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use tokei::{Language, LanguageType};
+
+use crate::{
+    busy_response, cache_get, charts::forbidden_response, count_coalesced, etag_identifier,
+    etag_matches, filter_language_types, format_amount, list_remote_refs, make_badge_style,
+    pool_outcome, repository_url, resolve_branch, resolve_tag, statistics_key, AppConfig,
+    CountOptions, RemoteRefs, RequestedRef, ResolvedRepository, StatisticsQuery, BLANKS,
+    BUSY_RETRY_AFTER_SECONDS, CODE, COMMENTS, CONTENT_TYPE_SVG, FILES, HASH_LENGTH, LINES,
+};
+
+const GREEN: &str = "#44cc11";
+const RED: &str = "#e05d44";
+const UNCHANGED: &str = "#9f9f9f";
+
+#[derive(serde::Deserialize)]
+pub struct DiffQuery {
+    base: Option<String>,
+    head: Option<String>,
+    r#type: Option<String>,
+    category: Option<String>,
+    label: Option<String>,
+    style: Option<String>,
+    logo: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Signed difference of the line counts, `head` minus `base`.
+#[derive(Default, serde::Serialize)]
+struct Delta {
+    code: i64,
+    comments: i64,
+    blanks: i64,
+    lines: i64,
+    files: i64,
+}
+
+impl Delta {
+    fn between(base: Option<&Language>, head: Option<&Language>) -> Self {
+        let count = |language: Option<&Language>, field: fn(&Language) -> usize| {
+            language.map_or(0, |language| field(language) as i64)
+        };
+        let delta = |field: fn(&Language) -> usize| count(head, field) - count(base, field);
+        Delta {
+            code: delta(|language| language.code),
+            comments: delta(|language| language.comments),
+            blanks: delta(|language| language.blanks),
+            lines: delta(Language::lines),
+            files: delta(|language| language.reports.len()),
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        [self.code, self.comments, self.blanks, self.files] == [0; 4]
+    }
+
+    fn add(&mut self, other: &Delta) {
+        self.code += other.code;
+        self.comments += other.comments;
+        self.blanks += other.blanks;
+        self.lines += other.lines;
+        self.files += other.files;
+    }
+
+    /// The difference in `category`, total lines by default.
+    fn of(&self, category: &str) -> i64 {
+        match category {
+            "code" => self.code,
+            "files" => self.files,
+            "blanks" => self.blanks,
+            "comments" => self.comments,
+            _ => self.lines,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Commit {
+    sha: String,
+    branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+impl From<ResolvedRepository> for Commit {
+    fn from(resolved: ResolvedRepository) -> Self {
+        Commit {
+            sha: resolved.sha,
+            branch: resolved.branch,
+            tag: resolved.tag,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct LanguageDelta {
+    language: &'static str,
+    #[serde(flatten)]
+    delta: Delta,
+}
+
+#[derive(serde::Serialize)]
+struct DiffBreakdown {
+    url: String,
+    base: Commit,
+    head: Commit,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    total: Delta,
+    /// Languages whose statistics changed, largest change in code first.
+    languages: Vec<LanguageDelta>,
+}
+
+/// Outcome of `lookup_diff`, see `Lookup`.
+enum DiffLookup {
+    Forbidden,
+    NotModified,
+    Busy,
+    Found(Box<Diff>),
+}
+
+/// Statistics of both commits of a diff.
+struct Diff {
+    url: String,
+    base: ResolvedRepository,
+    head: ResolvedRepository,
+    options: CountOptions,
+    etag: String,
+    base_languages: Vec<(LanguageType, Language)>,
+    head_languages: Vec<(LanguageType, Language)>,
+}
+
+impl Diff {
+    /// The difference of each language found in either commit (restricted to
+    /// the comma-separated `types` unless empty), and their total.
+    fn deltas(&self, types: &str) -> (Delta, Vec<(LanguageType, Delta)>) {
+        let base = filter_language_types(self.base_languages.clone(), types);
+        let head = filter_language_types(self.head_languages.clone(), types);
+        let find = |languages: &[(LanguageType, Language)], language_type: LanguageType| {
+            languages
+                .iter()
+                .find(|(found, _)| *found == language_type)
+                .map(|(_, language)| language.clone())
+        };
+        let mut language_types: Vec<LanguageType> = base
+            .iter()
+            .chain(&head)
+            .map(|(language_type, _)| *language_type)
+            .collect();
+        language_types.sort();
+        language_types.dedup();
+
+        let mut total = Delta::default();
+        let mut deltas: Vec<(LanguageType, Delta)> = language_types
+            .into_iter()
+            .map(|language_type| {
+                let delta = Delta::between(
+                    find(&base, language_type).as_ref(),
+                    find(&head, language_type).as_ref(),
+                );
+                total.add(&delta);
+                (language_type, delta)
+            })
+            .filter(|(_, delta)| !delta.is_zero())
+            .collect();
+        deltas.sort_by_key(|(_, delta)| std::cmp::Reverse(delta.code.abs()));
+        (total, deltas)
+    }
+}
+
+/// Resolves `base` and `head`, honours the request's `If-None-Match` header
+/// and returns the (possibly cached) statistics of both commits.
+async fn lookup_diff(
+    request: &HttpRequest,
+    data: &web::Data<AppConfig>,
+    (domain, user, repo): (String, String, String),
+    query: &DiffQuery,
+) -> actix_web::Result<DiffLookup> {
+    let non_empty = |v: &Option<String>| v.clone().filter(|v| !v.is_empty());
+    let (Some(base), Some(head)) = (non_empty(&query.base), non_empty(&query.head)) else {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Both base and head must be provided."
+        )));
+    };
+    if !matches!(query.statistics.requested_ref()?, RequestedRef::Default) {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Use base and head instead of branch, tag or sha."
+        )));
+    }
+    if query.statistics.embedded() || query.statistics.is_async() {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Diffs support neither embedded nor async."
+        )));
+    }
+    let options = query.statistics.count_options(&data.tokei_defaults)?;
+    let Some(url) = repository_url(data, &domain, &user, &repo)? else {
+        return Ok(DiffLookup::Forbidden);
+    };
+
+    let remote_url = url.clone();
+    let listed = data
+        .workers
+        .run(move || list_remote_refs(&remote_url))
+        .await;
+    let remote = match pool_outcome(listed, &url, &data.workers)? {
+        Some(listed) => {
+            listed.map_err(|e| actix_web::error::ErrorBadRequest(eyre::eyre!(e.to_string())))?
+        }
+        None => return Ok(DiffLookup::Busy),
+    };
+    let base = resolve_commit(&remote, &url, &base)?;
+    let head = resolve_commit(&remote, &url, &head)?;
+
+    let etag = format!(
+        "{}..{}",
+        etag_identifier(&base.sha, &base.reference(), &options, false),
+        etag_identifier(&head.sha, &head.reference(), &options, false)
+    );
+    if etag_matches(request, &etag) {
+        log::info!("{} - Diff {}..{} Not Modified", url, base.sha, head.sha);
+        return Ok(DiffLookup::NotModified);
+    }
+
+    let mut counted: Vec<Vec<(LanguageType, Language)>> = Vec::with_capacity(2);
+    for side in [&base, &head] {
+        let reference = side.reference();
+        let key = statistics_key(
+            &url,
+            &side.sha,
+            &reference,
+            data.ignore_filetypes.as_ref(),
+            &options,
+        );
+        let counts = match cache_get(data, &key).await {
+            Some(counts) => {
+                data.cache.stats().record_hit();
+                counts
+            }
+            None => {
+                data.cache.stats().record_miss();
+                match count_coalesced(data, &key, &url, &side.sha, &reference, &options).await? {
+                    Some(entry) => entry.value,
+                    None => return Ok(DiffLookup::Busy),
+                }
+            }
+        };
+        counted.push(counts.languages);
+    }
+    let head_languages = counted.pop().unwrap_or_default();
+    let base_languages = counted.pop().unwrap_or_default();
+
+    Ok(DiffLookup::Found(Box::new(Diff {
+        url,
+        base,
+        head,
+        options,
+        etag,
+        base_languages,
+        head_languages,
+    })))
+}
+
+/// Resolves one side of a diff: a commit SHA, `refs/heads/<branch>`,
+/// `refs/tags/<tag>`, or a bare name looked up among the tags, then among the
+/// branches, as git does.
+fn resolve_commit(
+    remote: &RemoteRefs,
+    url: &str,
+    name: &str,
+) -> actix_web::Result<ResolvedRepository> {
+    if name.len() == HASH_LENGTH && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(ResolvedRepository {
+            url: url.to_owned(),
+            sha: name.to_ascii_lowercase(),
+            branch: String::new(),
+            tag: None,
+        });
+    }
+    let (tag, branch) = match (
+        name.strip_prefix("refs/tags/"),
+        name.strip_prefix("refs/heads/"),
+    ) {
+        (Some(tag), _) => (Some(tag), None),
+        (_, Some(branch)) => (None, Some(branch)),
+        _ => (Some(name), Some(name)),
+    };
+    if let Some(tag) = tag {
+        if let Some(sha) = resolve_tag(remote, tag) {
+            return Ok(ResolvedRepository {
+                url: url.to_owned(),
+                sha,
+                branch: String::new(),
+                tag: Some(tag.to_owned()),
+            });
+        }
+    }
+    let branch = branch.filter(|branch| !branch.is_empty()).ok_or_else(|| {
+        actix_web::error::ErrorBadRequest(eyre::eyre!("Invalid reference provided: {}", name))
+    })?;
+    let (branch, sha) = resolve_branch(remote, url, branch).map_err(|_| {
+        actix_web::error::ErrorBadRequest(eyre::eyre!("Invalid reference provided: {}", name))
+    })?;
+    Ok(ResolvedRepository {
+        url: url.to_owned(),
+        sha,
+        branch,
+        tag: None,
+    })
+}
+
+/// Returns the difference of every language between `base` and `head`, and
+/// of their total.
+#[get("/api/v1/{domain}/{user}/{repo}/diff")]
+pub async fn diff_breakdown(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<DiffQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let diff = match lookup_diff(&request, &data, path.into_inner(), &query).await? {
+        DiffLookup::Found(diff) => *diff,
+        DiffLookup::NotModified => return Ok(HttpResponse::NotModified().finish()),
+        DiffLookup::Forbidden => {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "forbidden"
+            })))
+        }
+        DiffLookup::Busy => {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+                .json(serde_json::json!({
+                    "error": "busy"
+                })))
+        }
+    };
+    let (total, deltas) = diff.deltas(query.r#type.as_deref().unwrap_or_default());
+
+    let breakdown = DiffBreakdown {
+        languages: deltas
+            .into_iter()
+            .map(|(language_type, delta)| LanguageDelta {
+                language: language_type.name(),
+                delta,
+            })
+            .collect(),
+        total,
+        url: diff.url,
+        base: diff.base.into(),
+        head: diff.head.into(),
+        path: diff.options.path,
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((ETAG, EntityTag::new(false, diff.etag)))
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&breakdown)?))
+}
+
+/// Badge of the signed difference in `category` (lines of code by default)
+/// between `base` and `head`: green for growth, red for shrinkage.
+#[get("/b1/{domain}/{user}/{repo}/diff.svg")]
+pub async fn diff_badge(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<DiffQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let diff = match lookup_diff(&request, &data, path.into_inner(), &query).await? {
+        DiffLookup::Found(diff) => *diff,
+        DiffLookup::NotModified => return Ok(HttpResponse::NotModified().finish()),
+        DiffLookup::Forbidden => return forbidden_response().await,
+        DiffLookup::Busy => return busy_response().await,
+    };
+    let category = query.category.as_deref().unwrap_or("code");
+    let types = query.r#type.as_deref().unwrap_or_default();
+    let (total, _) = diff.deltas(types);
+    let amount = total.of(category);
+
+    let label = match &query.label {
+        Some(label) => label.clone(),
+        None => {
+            let category_label = match category {
+                "code" => CODE,
+                "files" => FILES,
+                "blanks" => BLANKS,
+                "comments" => COMMENTS,
+                _ => LINES,
+            };
+            // Name the language when the badge is about a single one.
+            let mut language_types = types
+                .split(',')
+                .filter_map(|s| s.parse::<LanguageType>().ok());
+            match (language_types.next(), language_types.next()) {
+                (Some(language_type), None) => {
+                    format!("{} {}", language_type.name(), category_label)
+                }
+                _ => category_label.to_owned(),
+            }
+        }
+    };
+    let (message, color) = match amount.signum() {
+        1 => (format!("+{}", format_amount(amount as usize)), GREEN),
+        -1 => (
+            format!("-{}", format_amount(amount.unsigned_abs() as usize)),
+            RED,
+        ),
+        _ => ("0".to_owned(), UNCHANGED),
+    };
+    log::info!(
+        "{} - Diff {}..{}: {} {}",
+        diff.url,
+        diff.base.sha,
+        diff.head.sha,
+        category,
+        message
+    );
+
+    let badge = make_badge_style(
+        &label,
+        &message,
+        color,
+        query.style.as_deref().unwrap_or("plastic"),
+        query.logo.as_deref().unwrap_or_default(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((ETAG, EntityTag::new(false, diff.etag)))
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(badge))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokei::{Language, LanguageType, Report};
+
+    use super::{resolve_commit, Delta, Diff};
+    use crate::{CountOptions, RemoteRefs, ResolvedRepository};
+
+    const URL: &str = "https://github.com/owner/repo";
+
+    fn language(code: usize, comments: usize, blanks: usize, files: usize) -> Language {
+        let mut language = Language::new();
+        language.code = code;
+        language.comments = comments;
+        language.blanks = blanks;
+        language.reports = (0..files)
+            .map(|i| Report::new(PathBuf::from(format!("{}", i))))
+            .collect();
+        language
+    }
+
+    fn commit(sha: &str) -> ResolvedRepository {
+        ResolvedRepository {
+            url: URL.to_owned(),
+            sha: sha.to_owned(),
+            branch: String::new(),
+            tag: None,
+        }
+    }
+
+    fn diff(
+        base_languages: Vec<(LanguageType, Language)>,
+        head_languages: Vec<(LanguageType, Language)>,
+    ) -> Diff {
+        Diff {
+            url: URL.to_owned(),
+            base: commit("base"),
+            head: commit("head"),
+            options: CountOptions::default(),
+            etag: String::new(),
+            base_languages,
+            head_languages,
+        }
+    }
+
+    fn fields(delta: &Delta) -> [i64; 5] {
+        [
+            delta.code,
+            delta.comments,
+            delta.blanks,
+            delta.lines,
+            delta.files,
+        ]
+    }
+
+    #[test]
+    fn deltas_are_head_minus_base() {
+        let delta = Delta::between(Some(&language(10, 5, 2, 3)), Some(&language(4, 8, 2, 4)));
+        assert_eq!(fields(&delta), [-6, 3, 0, -3, 1]);
+        assert_eq!(delta.of("code"), -6);
+        assert_eq!(delta.of("comments"), 3);
+        assert_eq!(delta.of("lines"), -3);
+    }
+
+    #[test]
+    fn languages_of_one_side_are_added_or_removed() {
+        let diff = diff(
+            vec![(LanguageType::Python, language(7, 1, 1, 1))],
+            vec![(LanguageType::Rust, language(20, 2, 3, 2))],
+        );
+        let (total, deltas) = diff.deltas("");
+        let deltas: Vec<(LanguageType, [i64; 5])> = deltas
+            .iter()
+            .map(|(language_type, delta)| (*language_type, fields(delta)))
+            .collect();
+        assert_eq!(
+            deltas,
+            [
+                (LanguageType::Rust, [20, 2, 3, 25, 2]),
+                (LanguageType::Python, [-7, -1, -1, -9, -1]),
+            ]
+        );
+        assert_eq!(fields(&total), [13, 1, 2, 16, 1]);
+    }
+
+    #[test]
+    fn unchanged_languages_are_left_out() {
+        let diff = diff(
+            vec![
+                (LanguageType::Rust, language(20, 2, 3, 2)),
+                (LanguageType::Toml, language(5, 0, 1, 1)),
+            ],
+            vec![
+                (LanguageType::Rust, language(20, 2, 3, 2)),
+                (LanguageType::Toml, language(6, 0, 1, 1)),
+            ],
+        );
+        let (total, deltas) = diff.deltas("");
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].0, LanguageType::Toml);
+        assert_eq!(fields(&total), [1, 0, 0, 1, 0]);
+
+        // Filtering on the unchanged language leaves nothing.
+        let (total, deltas) = diff.deltas("Rust");
+        assert!(deltas.is_empty());
+        assert!(total.is_zero());
+    }
+
+    #[test]
+    fn references_resolve_to_a_sha_then_a_tag_then_a_branch() {
+        let oid = |digit: char| digit.to_string().repeat(40);
+        let remote = RemoteRefs {
+            refs: vec![
+                ("refs/heads/main".to_owned(), oid('1')),
+                ("refs/heads/v1".to_owned(), oid('2')),
+                ("refs/tags/v1".to_owned(), oid('3')),
+                ("refs/heads/feature".to_owned(), oid('4')),
+                (format!("refs/heads/{}", oid('a')), oid('5')),
+            ],
+            head: None,
+        };
+        let resolve = |name: &str| resolve_commit(&remote, URL, name).unwrap();
+
+        let tag = resolve("v1");
+        assert_eq!((tag.sha, tag.tag.as_deref()), (oid('3'), Some("v1")));
+        let branch = resolve("refs/heads/v1");
+        assert_eq!(
+            (branch.sha, branch.branch, branch.tag),
+            (oid('2'), "v1".to_owned(), None)
+        );
+        let branch = resolve("feature");
+        assert_eq!(
+            (branch.sha, branch.branch),
+            (oid('4'), "feature".to_owned())
+        );
+        let sha = resolve(&oid('A'));
+        assert_eq!(
+            (sha.sha, sha.branch, sha.tag),
+            (oid('a'), String::new(), None)
+        );
+
+        assert!(resolve_commit(&remote, URL, "refs/tags/feature").is_err());
+        assert!(resolve_commit(&remote, URL, "missing").is_err());
+    }
+}
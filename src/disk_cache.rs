@@ -0,0 +1,133 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Optional on-disk copy of the statistics cache (`--cache-dir`), so that a
+//! restarted server doesn't have to clone every repository again.
+//!
+//! Entries are stored as JSON in a SQLite file, under the same keys as the
+//! in-memory cache and with their expiry time. Nothing is loaded up front:
+//! an entry is read the first time the in-memory cache misses it.
+
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Name of the SQLite file created in the cache directory.
+pub const FILE_NAME: &str = "statistics.sqlite3";
+
+pub struct DiskCache {
+    connection: Mutex<Connection>,
+    ttl_seconds: u64,
+}
+
+impl DiskCache {
+    /// Opens (or creates) the cache in `directory`, dropping expired entries.
+    pub fn open(directory: &Path, ttl_seconds: u64) -> eyre::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        let connection = Connection::open(directory.join(FILE_NAME))?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS statistics (
+                 key TEXT PRIMARY KEY,
+                 created_at INTEGER NOT NULL,
+                 expires_at INTEGER NOT NULL,
+                 value TEXT NOT NULL
+             );",
+        )?;
+        connection.execute(
+            "DELETE FROM statistics WHERE expires_at <= ?1",
+            params![now()],
+        )?;
+        Ok(DiskCache {
+            connection: Mutex::new(connection),
+            ttl_seconds,
+        })
+    }
+
+    /// Number of entries currently stored, expired or not.
+    pub fn entries(&self) -> eyre::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.query_row("SELECT COUNT(*) FROM statistics", [], |row| row.get(0))?)
+    }
+
+    /// The unexpired entry stored under `key`, if any.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> eyre::Result<Option<T>> {
+        let connection = self.connection.lock().unwrap();
+        let value: Option<String> = connection
+            .query_row(
+                "SELECT value FROM statistics WHERE key = ?1 AND expires_at > ?2",
+                params![key, now()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match value {
+            Some(value) => Some(serde_json::from_str(&value)?),
+            None => None,
+        })
+    }
+
+    /// Stores `value` under `key` for the cache TTL, replacing any previous
+    /// entry.
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> eyre::Result<()> {
+        let value = serde_json::to_string(value)?;
+        let created_at = now();
+        let expires_at = created_at.saturating_add(self.ttl_seconds as i64);
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO statistics (key, created_at, expires_at, value)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key, created_at, expires_at, value],
+        )?;
+        Ok(())
+    }
+
+    /// Keys of every unexpired entry.
+    pub fn keys(&self) -> eyre::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT key FROM statistics WHERE expires_at > ?1")?;
+        let keys = statement
+            .query_map(params![now()], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(keys)
+    }
+
+    /// Removes the entry stored under `key`, `true` if there was one.
+    pub fn remove(&self, key: &str) -> eyre::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection.execute("DELETE FROM statistics WHERE key = ?1", params![key])?;
+        Ok(removed > 0)
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
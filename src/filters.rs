@@ -0,0 +1,211 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Request-level `include` / `exclude` glob patterns narrowing which files of
+//! a repository are counted.
+//!
+//! Patterns follow `.gitignore` conventions and are matched against paths
+//! relative to the repository root: a pattern without a `/` matches a file or
+//! directory name at any depth, a leading `/` anchors a pattern to the root,
+//! and a pattern matching a directory matches everything below it.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Maximum number of patterns accepted in a single `include` or `exclude` list.
+pub const MAX_PATTERNS: usize = 16;
+/// Maximum length in bytes of a single pattern.
+pub const MAX_PATTERN_LENGTH: usize = 256;
+
+/// Splits a comma-separated list of glob patterns, keeping commas inside
+/// `{a,b}` alternations. Patterns are trimmed, validated, de-duplicated and
+/// sorted so that equivalent lists produce the same cache key.
+pub fn parse_patterns(list: Option<&str>) -> actix_web::Result<Vec<String>> {
+    let Some(list) = list else {
+        return Ok(Vec::new());
+    };
+
+    let mut patterns: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    for c in list.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                patterns.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    patterns.push(current);
+
+    let mut patterns: Vec<String> = patterns
+        .into_iter()
+        .map(|pattern| pattern.trim().to_owned())
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    patterns.sort();
+    patterns.dedup();
+
+    if patterns.len() > MAX_PATTERNS {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Too many glob patterns provided (at most {}).",
+            MAX_PATTERNS
+        )));
+    }
+    for pattern in &patterns {
+        if !is_valid(pattern) {
+            return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                "Invalid glob pattern provided."
+            )));
+        }
+    }
+    Ok(patterns)
+}
+
+/// Whether a single pattern is short enough and compiles.
+pub fn is_valid(pattern: &str) -> bool {
+    pattern.len() <= MAX_PATTERN_LENGTH && build_set(&[pattern.to_owned()]).is_ok()
+}
+
+/// Translates a `.gitignore`-style pattern into the globs matching it against
+/// root-relative paths: the pattern itself and everything below it.
+fn expand(pattern: &str) -> [String; 2] {
+    let pattern = pattern.trim_end_matches('/');
+    let anchored = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_owned(),
+        None if pattern.contains('/') => pattern.to_owned(),
+        None => format!("**/{}", pattern),
+    };
+    [format!("{}/**", anchored), anchored]
+}
+
+/// Compiles a list of patterns into a single set, `None` when empty.
+pub fn build_set(patterns: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        for glob in expand(pattern) {
+            builder.add(GlobBuilder::new(&glob).literal_separator(true).build()?);
+        }
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Compiled `include` and `exclude` lists.
+pub struct GlobFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl GlobFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, globset::Error> {
+        Ok(GlobFilter {
+            include: build_set(include)?,
+            exclude: build_set(exclude)?,
+        })
+    }
+
+    /// Whether a file, given by its path relative to the repository root,
+    /// should be counted.
+    pub fn is_match(&self, path: &std::path::Path) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|set| set.is_match(path))
+    }
+
+    /// Whether a directory, given by its path relative to the repository
+    /// root, is excluded along with everything below it.
+    pub fn is_excluded(&self, path: &std::path::Path) -> bool {
+        self.exclude.as_ref().is_some_and(|set| set.is_match(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{is_valid, parse_patterns, GlobFilter, MAX_PATTERNS, MAX_PATTERN_LENGTH};
+
+    #[test]
+    fn patterns_are_split_outside_alternations() {
+        assert_eq!(parse_patterns(None).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            parse_patterns(Some(" src/**/*.{rs,toml}, docs ,,docs")).unwrap(),
+            ["docs", "src/**/*.{rs,toml}"]
+        );
+    }
+
+    #[test]
+    fn pattern_count_is_bounded() {
+        let list = |count: usize| {
+            (0..count)
+                .map(|i| format!("dir{}", i))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert_eq!(
+            parse_patterns(Some(&list(MAX_PATTERNS))).unwrap().len(),
+            MAX_PATTERNS
+        );
+        assert!(parse_patterns(Some(&list(MAX_PATTERNS + 1))).is_err());
+        // Duplicates don't count twice.
+        let repeated = vec!["src"; MAX_PATTERNS + 1].join(",");
+        assert_eq!(parse_patterns(Some(&repeated)).unwrap(), ["src"]);
+    }
+
+    #[test]
+    fn pattern_length_and_syntax_are_validated() {
+        assert!(is_valid(&"a".repeat(MAX_PATTERN_LENGTH)));
+        assert!(!is_valid(&"a".repeat(MAX_PATTERN_LENGTH + 1)));
+        assert!(!is_valid("src/{a,b"));
+        assert!(!is_valid("[z-a]"));
+        assert!(parse_patterns(Some("src,src/{a,b")).is_err());
+    }
+
+    #[test]
+    fn patterns_may_contain_spaces_and_quotes() {
+        let patterns = parse_patterns(Some(r#"my dir/**,"quoted".rs"#)).unwrap();
+        assert_eq!(patterns, [r#""quoted".rs"#, "my dir/**"]);
+        let filter = GlobFilter::new(&patterns, &[]).unwrap();
+        assert!(filter.is_match(Path::new("my dir/main.rs")));
+        assert!(filter.is_match(Path::new(r#"src/"quoted".rs"#)));
+        assert!(!filter.is_match(Path::new("my_dir/main.rs")));
+    }
+
+    #[test]
+    fn patterns_follow_gitignore_conventions() {
+        let filter = GlobFilter::new(&[], &["target".to_owned(), "/docs".to_owned()]).unwrap();
+        assert!(!filter.is_match(Path::new("target/debug/main.rs")));
+        assert!(!filter.is_match(Path::new("crates/a/target/lib.rs")));
+        assert!(!filter.is_match(Path::new("docs/index.md")));
+        assert!(filter.is_match(Path::new("crates/docs/index.md")));
+        assert!(filter.is_excluded(Path::new("crates/a/target")));
+
+        let filter = GlobFilter::new(&["*.rs".to_owned()], &[]).unwrap();
+        assert!(filter.is_match(Path::new("src/deep/main.rs")));
+        assert!(!filter.is_match(Path::new("Cargo.toml")));
+    }
+}
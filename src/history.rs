@@ -0,0 +1,573 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Optional history of the statistics counted for each commit
+//! (`--history-db`), to chart how a codebase grows over time without running
+//! tokei in CI. Every commit the server counts is recorded with its commit
+//! time, reference and per-language totals, in a SQLite file. Commits counted
+//! with different options (a `path`, `include` patterns, the server's ignored
+//! file types...) make separate series. `backfill` counts a sample of the older commits of a branch.
+
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective, ContentType, CACHE_CONTROL},
+    web, HttpResponse,
+};
+use git2::Oid;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    count_commit, options_identifier, repository_url, with_fetched_commit, AppConfig, CountOptions,
+    Counts, RequestedRef, ResolvedRepository, StatisticsQuery,
+};
+
+/// Commits a single backfill may count.
+pub const MAX_SAMPLES: usize = 1000;
+
+/// Counts of one language, or of a whole commit.
+#[derive(Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct Totals {
+    code: usize,
+    comments: usize,
+    blanks: usize,
+    lines: usize,
+    files: usize,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.code += other.code;
+        self.comments += other.comments;
+        self.blanks += other.blanks;
+        self.lines += other.lines;
+        self.files += other.files;
+    }
+}
+
+/// A recorded commit.
+#[derive(serde::Serialize)]
+pub struct Point {
+    sha: String,
+    branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    /// Commit time, unknown for statistics cached by older versions.
+    committed_at: Option<i64>,
+    counted_at: i64,
+    total: Totals,
+    languages: BTreeMap<String, Totals>,
+}
+
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    /// Opens (or creates) the history file at `path`.
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS history (
+                 url TEXT NOT NULL,
+                 options TEXT NOT NULL,
+                 sha TEXT NOT NULL,
+                 branch TEXT NOT NULL,
+                 tag TEXT,
+                 committed_at INTEGER,
+                 counted_at INTEGER NOT NULL,
+                 languages TEXT NOT NULL,
+                 PRIMARY KEY (url, options, sha)
+             );",
+        )?;
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Number of recorded commits.
+    pub fn entries(&self) -> eyre::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection.query_row("SELECT COUNT(*) FROM history", [], |row| row.get(0))?)
+    }
+
+    /// Records the statistics of commit `sha`, counted with `options` (see
+    /// `options_identifier`) for `reference`. A commit recorded again
+    /// keeps the branch it was first recorded for, unless it had none.
+    pub fn record(
+        &self,
+        url: &str,
+        options: &str,
+        sha: &str,
+        reference: &str,
+        counts: &Counts,
+    ) -> eyre::Result<()> {
+        let (branch, tag) = match reference.strip_prefix("refs/tags/") {
+            Some(tag) => ("", Some(tag)),
+            None if reference == sha => ("", None),
+            None => (reference, None),
+        };
+        let languages: BTreeMap<&str, Totals> = counts
+            .languages
+            .iter()
+            .map(|(language_type, language)| {
+                (
+                    language_type.name(),
+                    Totals {
+                        code: language.code,
+                        comments: language.comments,
+                        blanks: language.blanks,
+                        lines: language.lines(),
+                        files: language.reports.len(),
+                    },
+                )
+            })
+            .collect();
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO history
+                 (url, options, sha, branch, tag, committed_at, counted_at, languages)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (url, options, sha) DO UPDATE SET
+                 branch = CASE WHEN branch = '' THEN excluded.branch ELSE branch END,
+                 tag = COALESCE(tag, excluded.tag),
+                 committed_at = COALESCE(excluded.committed_at, committed_at),
+                 counted_at = excluded.counted_at,
+                 languages = excluded.languages",
+            params![
+                url,
+                options,
+                sha,
+                branch,
+                tag,
+                counts.committed_at,
+                now(),
+                serde_json::to_string(&languages)?
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Whether commit `sha` was recorded with `options`.
+    pub fn contains(&self, url: &str, options: &str, sha: &str) -> eyre::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection
+            .query_row(
+                "SELECT 1 FROM history WHERE url = ?1 AND options = ?2 AND sha = ?3",
+                params![url, options, sha],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    /// The commits recorded with `options`, optionally only those of
+    /// `branch`, oldest first.
+    pub fn series(
+        &self,
+        url: &str,
+        options: &str,
+        branch: Option<&str>,
+    ) -> eyre::Result<Vec<Point>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT sha, branch, tag, committed_at, counted_at, languages FROM history
+             WHERE url = ?1 AND options = ?2 AND (?3 IS NULL OR branch = ?3)
+             ORDER BY COALESCE(committed_at, counted_at), counted_at",
+        )?;
+        let rows = statement
+            .query_map(params![url, options, branch], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(sha, branch, tag, committed_at, counted_at, languages)| {
+                let languages: BTreeMap<String, Totals> = serde_json::from_str(&languages)?;
+                Ok(Point {
+                    sha,
+                    branch,
+                    tag,
+                    committed_at,
+                    counted_at,
+                    total: Totals::default(),
+                    languages,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Counts up to `samples` commits evenly spread along the first-parent
+/// history of `resolved` (its head and root commit included) and records
+/// them, skipping the commits already recorded.
+pub async fn backfill(
+    data: web::Data<AppConfig>,
+    resolved: ResolvedRepository,
+    options: CountOptions,
+    samples: usize,
+) {
+    let Some(history) = data.history.clone() else {
+        return;
+    };
+    let url = &resolved.url;
+    log::info!(
+        "{}#{}#{} Backfilling the history",
+        url,
+        resolved.sha,
+        resolved.reference()
+    );
+    match backfill_commits(&data, history, &resolved, &options, samples).await {
+        Ok(counted) => log::info!("{} - Backfilled {} commits", url, counted),
+        Err(e) => log::warn!("{} - Unable to backfill the history: {}", url, e),
+    }
+}
+
+/// Fetches the whole history of `resolved`, into its mirror or a temporary
+/// repository, then counts and records the sampled commits. Every step is a
+/// job of its own on the worker pool, so that badge requests aren't held up
+/// by a long backfill; the blob cache makes counting successive commits
+/// cheap. Returns the number of commits counted.
+async fn backfill_commits(
+    data: &web::Data<AppConfig>,
+    history: Arc<History>,
+    resolved: &ResolvedRepository,
+    options: &CountOptions,
+    samples: usize,
+) -> eyre::Result<usize> {
+    let dir = tempfile::TempDir::new()?;
+    let repository = dir.path().join("repository.git");
+    let (url, reference) = (resolved.url.clone(), resolved.reference());
+    let identifier = options_identifier(data.ignore_filetypes.as_ref(), options);
+    let on_pool = |e: crate::pool::PoolError| eyre::eyre!(e.to_string());
+
+    let sampled = {
+        let (url, sha, reference) = (url.clone(), resolved.sha.clone(), reference.clone());
+        let (history, identifier) = (history.clone(), identifier.clone());
+        let (mirrors, repository) = (data.mirrors.clone(), repository.clone());
+        move || {
+            with_fetched_commit(
+                &url,
+                &sha,
+                &reference,
+                mirrors.as_deref(),
+                Some(&repository),
+                |repo, head, _| {
+                    let mut walk = repo.revwalk()?;
+                    walk.push(head.id())?;
+                    walk.simplify_first_parent()?;
+                    let commits = walk.collect::<Result<Vec<Oid>, _>>()?;
+                    let mut sampled = Vec::new();
+                    for oid in sample(&commits, samples) {
+                        if !history.contains(&url, &identifier, &oid.to_string())? {
+                            sampled.push(oid);
+                        }
+                    }
+                    Ok(sampled)
+                },
+            )
+        }
+    };
+    let sampled = data.workers.run(sampled).await.map_err(on_pool)??;
+
+    for (counted, oid) in sampled.iter().enumerate() {
+        let sha = oid.to_string();
+        let (url, reference) = (url.clone(), reference.clone());
+        let (history, identifier) = (history.clone(), identifier.clone());
+        let (mirrors, repository) = (data.mirrors.clone(), repository.clone());
+        let ignore_filetypes = data.ignore_filetypes.clone();
+        let options = options.clone();
+        let job = move || {
+            with_fetched_commit(
+                &url,
+                &sha,
+                &sha,
+                mirrors.as_deref(),
+                Some(&repository),
+                |repo, commit, scratch| {
+                    let counts = count_commit(
+                        repo,
+                        commit,
+                        &url,
+                        ignore_filetypes.as_ref(),
+                        &options,
+                        scratch,
+                    )?;
+                    history.record(&url, &identifier, &sha, &reference, &counts)
+                },
+            )
+        };
+        if let Err(e) = data.workers.run(job).await.map_err(on_pool)? {
+            return Err(eyre::eyre!("{} after {} commits", e, counted));
+        }
+    }
+    Ok(sampled.len())
+}
+
+/// `samples` commits evenly spread over `commits`, first and last included.
+fn sample(commits: &[Oid], samples: usize) -> Vec<Oid> {
+    match samples {
+        0 => Vec::new(),
+        _ if commits.len() <= samples => commits.to_vec(),
+        1 => commits[..1].to_vec(),
+        _ => (0..samples)
+            .map(|i| commits[i * (commits.len() - 1) / (samples - 1)])
+            .collect(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct HistoryQuery {
+    r#type: Option<String>,
+    format: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+#[derive(serde::Serialize)]
+struct HistoryBreakdown {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    /// Recorded commits, oldest first.
+    commits: Vec<Point>,
+}
+
+/// Returns the statistics recorded for the commits of a repository counted
+/// with the request's options, as JSON or, with `format=csv`, as CSV with a
+/// row per commit and language.
+#[get("/api/v1/{domain}/{user}/{repo}/history")]
+pub async fn commit_history(
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<HistoryQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(history) = &data.history else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "history is disabled"
+        })));
+    };
+    let (domain, user, repo) = path.into_inner();
+    let Some(url) = repository_url(&data, &domain, &user, &repo)? else {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "forbidden"
+        })));
+    };
+    let branch = match query.statistics.requested_ref()? {
+        RequestedRef::Default => None,
+        RequestedRef::Branch(branch) => Some(branch),
+        RequestedRef::Tag(_) | RequestedRef::Sha(_) => {
+            return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                "The history can only be filtered by branch."
+            )))
+        }
+    };
+    let options = query.statistics.count_options(&data.tokei_defaults)?;
+
+    let mut commits = history
+        .series(
+            &url,
+            &options_identifier(data.ignore_filetypes.as_ref(), &options),
+            branch.as_deref(),
+        )
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let types: Vec<String> = query
+        .r#type
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|name| name.parse::<tokei::LanguageType>().ok())
+        .map(|language_type| language_type.name().to_owned())
+        .collect();
+    for point in &mut commits {
+        if !types.is_empty() {
+            point.languages.retain(|name, _| types.contains(name));
+        }
+        for totals in point.languages.values() {
+            point.total.add(totals);
+        }
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])));
+    if query.format.as_deref() == Some("csv") {
+        return Ok(response
+            .content_type("text/csv; charset=utf-8")
+            .body(to_csv(&commits)));
+    }
+    Ok(response
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&HistoryBreakdown {
+            url,
+            path: options.path,
+            commits,
+        })?))
+}
+
+/// One line per commit and language, after a header line.
+fn to_csv(commits: &[Point]) -> String {
+    let mut csv = String::from(
+        "sha,branch,tag,committed_at,counted_at,language,code,comments,blanks,lines,files\n",
+    );
+    for point in commits {
+        for (language, totals) in &point.languages {
+            let fields = [
+                point.sha.clone(),
+                csv_field(&point.branch),
+                csv_field(point.tag.as_deref().unwrap_or_default()),
+                point
+                    .committed_at
+                    .map(|time| time.to_string())
+                    .unwrap_or_default(),
+                point.counted_at.to_string(),
+                csv_field(language),
+                totals.code.to_string(),
+                totals.comments.to_string(),
+                totals.blanks.to_string(),
+                totals.lines.to_string(),
+                totals.files.to_string(),
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+/// Quotes a CSV field when it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+    use tokei::{Language, LanguageType};
+
+    use super::{csv_field, sample, to_csv, History};
+    use crate::Counts;
+
+    fn oids(count: u8) -> Vec<Oid> {
+        (0..count)
+            .map(|i| Oid::from_bytes(&[i; 20]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn samples_are_evenly_spread() {
+        let commits = oids(10);
+        assert!(sample(&commits, 0).is_empty());
+        assert_eq!(sample(&commits, 1), [commits[0]]);
+        assert_eq!(sample(&commits, 2), [commits[0], commits[9]]);
+        assert_eq!(
+            sample(&commits, 4),
+            [commits[0], commits[3], commits[6], commits[9]]
+        );
+        assert_eq!(sample(&commits, 10), commits);
+        assert_eq!(sample(&commits, 20), commits);
+        assert!(sample(&[], 5).is_empty());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("main"), "main");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn recorded_commits_are_listed_oldest_first() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let history = History::open(&dir.path().join("history.sqlite3")).unwrap();
+        let counts = |code: usize, committed_at: i64| {
+            let mut rust = Language::new();
+            rust.code = code;
+            Counts {
+                languages: vec![(LanguageType::Rust, rust)],
+                default_category: None,
+                committed_at: Some(committed_at),
+            }
+        };
+        let url = "https://github.com/user/repo";
+        let (old, new) = ("1".repeat(40), "2".repeat(40));
+        history
+            .record(url, "", &new, "main", &counts(20, 200))
+            .unwrap();
+        history
+            .record(url, "", &old, "main", &counts(10, 100))
+            .unwrap();
+        history
+            .record(url, "#path=src", &old, "main", &counts(5, 100))
+            .unwrap();
+        assert!(history.contains(url, "", &old).unwrap());
+        assert!(!history.contains(url, "#exclude=x", &old).unwrap());
+
+        let series = history.series(url, "", Some("main")).unwrap();
+        let shas: Vec<&str> = series.iter().map(|point| point.sha.as_str()).collect();
+        assert_eq!(shas, [old.as_str(), new.as_str()]);
+        assert!(history.series(url, "", Some("dev")).unwrap().is_empty());
+
+        let csv = to_csv(&series);
+        let mut lines = csv.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("sha,branch,tag,committed_at"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with(&format!("{},main,,100,", old)));
+        assert_eq!(lines.count(), 1);
+    }
+}
@@ -0,0 +1,482 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Asynchronous statistics jobs, for repositories whose clone and count
+//! outlast the timeouts of the proxies in front of the service. A job is
+//! started by `POST /api/v1/{domain}/{user}/{repo}/jobs`, or by a statistics
+//! request with `async=true` missing the cache, and is polled on
+//! `/jobs/{id}` until its statistics are cached.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    get,
+    http::header::{CacheControl, CacheDirective, CACHE_CONTROL, LOCATION, RETRY_AFTER},
+    post, web, HttpRequest, HttpResponse,
+};
+use tokei::Language;
+
+use crate::{
+    api::LanguageSummary, resolve_repository, start_job, statistics_key, AppConfig, CountOutcome,
+    Counts, Resolution, ResolvedRepository, StatisticsQuery, BUSY_RETRY_AFTER_SECONDS,
+};
+
+/// Finished jobs are forgotten after this long.
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+/// Jobs remembered at most, the oldest finished ones are forgotten first.
+const MAX_JOBS: usize = 10_000;
+/// Seconds a client is asked to wait before polling a running job again.
+pub const POLL_SECONDS: u32 = 5;
+
+/// Progress of a job, and of the count it waits for.
+#[derive(Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Waiting for a clone slot.
+    Queued,
+    Cloning,
+    Counting,
+    Done,
+    Failed,
+}
+
+struct Job {
+    /// Statistics key of the counted commit.
+    key: String,
+    url: String,
+    sha: String,
+    branch: String,
+    tag: Option<String>,
+    /// Where the statistics are served once the job is done.
+    result: String,
+    phase: Phase,
+    created: SystemTime,
+    cloning: Option<SystemTime>,
+    counting: Option<SystemTime>,
+    finished: Option<SystemTime>,
+    error: Option<String>,
+    /// Summed statistics, once done.
+    total: Option<Language>,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        self.finished.is_some()
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        let now = SystemTime::now();
+        match phase {
+            Phase::Cloning => self.cloning = Some(now),
+            Phase::Counting => self.counting = Some(now),
+            Phase::Done | Phase::Failed => self.finished = Some(now),
+            Phase::Queued => {}
+        }
+        self.phase = phase;
+    }
+}
+
+/// Jobs by ID, along with the phase of the counts they wait for.
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Phase of the counts running on the worker pool, keyed like the
+    /// statistics cache. Counts aren't necessarily started by a job.
+    counts: Mutex<HashMap<String, Phase>>,
+}
+
+impl Jobs {
+    /// Registers a job counting the commit stored under `key`, unless one
+    /// is already running for it. Returns the job ID, and whether the job is
+    /// new and should be started.
+    pub fn create(
+        &self,
+        key: &str,
+        resolved: &ResolvedRepository,
+        result: String,
+    ) -> (String, bool) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some((id, _)) = jobs
+            .iter()
+            .find(|(_, job)| job.key == key && !job.is_finished())
+        {
+            return (id.clone(), false);
+        }
+        prune(&mut jobs);
+
+        let mut job = Job {
+            key: key.to_owned(),
+            url: resolved.url.clone(),
+            sha: resolved.sha.clone(),
+            branch: resolved.branch.clone(),
+            tag: resolved.tag.clone(),
+            result,
+            phase: Phase::Queued,
+            created: SystemTime::now(),
+            cloning: None,
+            counting: None,
+            finished: None,
+            error: None,
+            total: None,
+        };
+        // The count may have been started by a synchronous request.
+        if let Some(phase) = self.counts.lock().unwrap().get(key) {
+            job.enter(*phase);
+        }
+        let id = job_id();
+        jobs.insert(id.clone(), job);
+        (id, true)
+    }
+
+    /// Records the phase of the count of `key`, and of the jobs waiting for it.
+    pub fn set_phase(&self, key: &str, phase: Phase) {
+        self.counts.lock().unwrap().insert(key.to_owned(), phase);
+        for job in self.jobs.lock().unwrap().values_mut() {
+            if job.key == key && !job.is_finished() {
+                job.enter(phase);
+            }
+        }
+    }
+
+    /// Forgets the phase of a count that is over.
+    pub fn count_finished(&self, key: &str) {
+        self.counts.lock().unwrap().remove(key);
+    }
+
+    /// Records the outcome of a job.
+    pub fn finish(&self, id: &str, outcome: &CountOutcome) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        match outcome {
+            CountOutcome::Counted(entry) => {
+                job.total = Some(total(&entry.value));
+                job.enter(Phase::Done);
+            }
+            CountOutcome::Busy => {
+                job.error = Some(crate::BUSY.to_owned());
+                job.enter(Phase::Failed);
+            }
+            CountOutcome::Invalid(message) | CountOutcome::Failed(message) => {
+                job.error = Some(message.clone());
+                job.enter(Phase::Failed);
+            }
+        }
+    }
+
+    /// Current state of a job, `None` if it is unknown or was forgotten.
+    fn status(&self, id: &str) -> Option<serde_json::Value> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+        let millis = |from: Option<SystemTime>, to: Option<SystemTime>| {
+            let elapsed = to
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(from?)
+                .ok()?;
+            Some(elapsed.as_millis() as u64)
+        };
+        let started = job.cloning.or(job.counting);
+        Some(serde_json::json!({
+            "id": id,
+            "status": job.phase,
+            "url": job.url,
+            "sha": job.sha,
+            "branch": job.branch,
+            "tag": job.tag,
+            "result": job.result,
+            "created_at": unix_seconds(job.created),
+            "finished_at": job.finished.map(unix_seconds),
+            "queued_ms": millis(Some(job.created), started.or(job.finished)),
+            "cloning_ms": millis(job.cloning, job.counting.or(job.finished)),
+            "counting_ms": millis(job.counting, job.finished),
+            "elapsed_ms": millis(Some(job.created), job.finished),
+            "total": job.total.as_ref().map(LanguageSummary::from),
+            "error": job.error,
+        }))
+    }
+}
+
+/// Forgets the jobs finished for longer than `RETENTION`, and the oldest
+/// finished ones beyond `MAX_JOBS`.
+fn prune(jobs: &mut HashMap<String, Job>) {
+    jobs.retain(|_, job| {
+        job.finished
+            .is_none_or(|finished| finished.elapsed().unwrap_or_default() < RETENTION)
+    });
+    if jobs.len() < MAX_JOBS {
+        return;
+    }
+    let mut finished: Vec<(SystemTime, String)> = jobs
+        .iter()
+        .filter_map(|(id, job)| Some((job.finished?, id.clone())))
+        .collect();
+    finished.sort();
+    for (_, id) in finished.into_iter().take(jobs.len() + 1 - MAX_JOBS) {
+        jobs.remove(&id);
+    }
+}
+
+/// Statistics of every language summed up.
+fn total(counts: &Counts) -> Language {
+    let mut total = Language::new();
+    for (_, language) in &counts.languages {
+        total += language.clone();
+    }
+    total
+}
+
+/// Random, unguessable job ID.
+fn job_id() -> String {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes).expect("the system random generator failed");
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// The URL of a request without its `async` parameter, where the statistics
+/// of its job are served once done.
+pub fn result_url(request: &HttpRequest) -> String {
+    let query: Vec<&str> = request
+        .query_string()
+        .split('&')
+        .filter(|parameter| !parameter.is_empty() && !parameter.starts_with("async="))
+        .collect();
+    if query.is_empty() {
+        request.path().to_owned()
+    } else {
+        format!("{}?{}", request.path(), query.join("&"))
+    }
+}
+
+/// `202 Accepted` response pointing at a job, with its current state.
+pub fn accepted(data: &AppConfig, id: &str) -> HttpResponse {
+    HttpResponse::Accepted()
+        .insert_header((LOCATION, format!("/jobs/{}", id)))
+        .insert_header((RETRY_AFTER, POLL_SECONDS))
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .json(data.jobs.status(id))
+}
+
+/// Starts counting the statistics of a repository, taking the same reference
+/// and count options as the JSON API. The job is already done when the
+/// statistics were cached.
+#[post("/api/v1/{domain}/{user}/{repo}/jobs")]
+pub async fn create_job(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    path: web::Path<(String, String, String)>,
+    web::Query(query): web::Query<StatisticsQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let (domain, user, repo) = path.into_inner();
+    let requested = query.requested_ref()?;
+    let options = query.count_options(&data.tokei_defaults)?;
+    let resolved = match resolve_repository(&data, &domain, &user, &repo, &requested).await? {
+        Resolution::Resolved(resolved) => resolved,
+        Resolution::Forbidden => {
+            return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "forbidden"
+            })))
+        }
+        Resolution::Busy => {
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+                .json(serde_json::json!({ "error": "busy" })))
+        }
+    };
+    let key = statistics_key(
+        &resolved.url,
+        &resolved.sha,
+        &resolved.reference(),
+        data.ignore_filetypes.as_ref(),
+        &options,
+    );
+    let mut result = format!("/api/v1/{}/{}/{}/languages", domain, user, repo);
+    if !request.query_string().is_empty() {
+        result.push('?');
+        result.push_str(request.query_string());
+    }
+    let id = start_job(&data, &resolved, key, &options, result).await;
+    Ok(accepted(&data, &id))
+}
+
+/// State of a job: `queued`, `cloning`, `counting`, `done` or `failed`,
+/// with its timings.
+#[get("/jobs/{id}")]
+pub async fn job_status(data: web::Data<AppConfig>, id: web::Path<String>) -> HttpResponse {
+    match data.jobs.status(&id) {
+        Some(status) => HttpResponse::Ok()
+            .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+            .json(status),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "error": "unknown job" })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App,
+    };
+    use cached::Return;
+    use tokei::{Language, LanguageType};
+
+    use super::{job_status, prune, Job, Jobs, Phase, MAX_JOBS, RETENTION};
+    use crate::{CountOutcome, Counts, ResolvedRepository};
+
+    fn resolved() -> ResolvedRepository {
+        ResolvedRepository {
+            url: "https://github.com/owner/repo".to_owned(),
+            sha: "1".repeat(40),
+            branch: "main".to_owned(),
+            tag: None,
+        }
+    }
+
+    fn counted(code: usize) -> CountOutcome {
+        let mut language = Language::new();
+        language.code = code;
+        CountOutcome::Counted(Return::new(Counts {
+            languages: vec![(LanguageType::Rust, language)],
+            default_category: None,
+            committed_at: None,
+        }))
+    }
+
+    fn status(jobs: &Jobs, id: &str) -> serde_json::Value {
+        jobs.status(id).unwrap()
+    }
+
+    /// A job finished at `finished`, or still running.
+    fn job(finished: Option<SystemTime>) -> Job {
+        Job {
+            key: String::new(),
+            url: String::new(),
+            sha: String::new(),
+            branch: String::new(),
+            tag: None,
+            result: String::new(),
+            phase: Phase::Queued,
+            created: SystemTime::now(),
+            cloning: None,
+            counting: None,
+            finished,
+            error: None,
+            total: None,
+        }
+    }
+
+    #[test]
+    fn jobs_follow_the_phases_of_their_count() {
+        let jobs = Jobs::default();
+        let (id, started) = jobs.create("key", &resolved(), "/result".to_owned());
+        assert!(started);
+        assert_eq!(status(&jobs, &id)["status"], "queued");
+
+        jobs.set_phase("key", Phase::Cloning);
+        assert_eq!(status(&jobs, &id)["status"], "cloning");
+        // A second request for the same count joins the running job.
+        assert_eq!(
+            jobs.create("key", &resolved(), "/result".to_owned()),
+            (id.clone(), false)
+        );
+        jobs.set_phase("key", Phase::Counting);
+        jobs.finish(&id, &counted(42));
+        jobs.count_finished("key");
+        let done = status(&jobs, &id);
+        assert_eq!(done["status"], "done");
+        assert_eq!(done["total"]["code"], 42);
+        assert!(done["cloning_ms"].is_u64() && done["counting_ms"].is_u64());
+        assert!(done["error"].is_null());
+        // Later phases of another count don't touch a finished job.
+        jobs.set_phase("key", Phase::Cloning);
+        assert_eq!(status(&jobs, &id)["status"], "done");
+
+        // A finished job isn't joined, and a job created while its count runs
+        // starts in the count's phase.
+        let (again, started) = jobs.create("key", &resolved(), "/result".to_owned());
+        assert!(started && again != id);
+        assert_eq!(status(&jobs, &again)["status"], "cloning");
+        jobs.finish(&again, &CountOutcome::Failed("boom".to_owned()));
+        let failed = status(&jobs, &again);
+        assert_eq!(failed["status"], "failed");
+        assert_eq!(failed["error"], "boom");
+        assert!(failed["total"].is_null());
+    }
+
+    #[test]
+    fn old_and_excess_finished_jobs_are_pruned() {
+        let now = SystemTime::now();
+        let mut jobs: HashMap<String, Job> = HashMap::new();
+        jobs.insert("expired".to_owned(), job(Some(now - RETENTION)));
+        jobs.insert("recent".to_owned(), job(Some(now)));
+        jobs.insert("running".to_owned(), job(None));
+        prune(&mut jobs);
+        let mut ids: Vec<&String> = jobs.keys().collect();
+        ids.sort();
+        assert_eq!(ids, ["recent", "running"]);
+
+        // Room is made for a new job by forgetting the oldest finished ones,
+        // never a running one.
+        jobs.clear();
+        for i in 0..MAX_JOBS - 1 {
+            let finished = now - Duration::from_millis(i as u64);
+            jobs.insert(format!("finished-{}", i), job(Some(finished)));
+        }
+        jobs.insert("running".to_owned(), job(None));
+        prune(&mut jobs);
+        assert_eq!(jobs.len(), MAX_JOBS - 1);
+        assert!(jobs.contains_key("running"));
+        assert!(jobs.contains_key("finished-0"));
+        assert!(!jobs.contains_key(&format!("finished-{}", MAX_JOBS - 2)));
+    }
+
+    #[actix_web::test]
+    async fn unknown_jobs_are_not_found() {
+        let data = web::Data::new(crate::tests::app_config());
+        let (id, _) = data.jobs.create("key", &resolved(), "/result".to_owned());
+        let app = init_service(App::new().app_data(data).service(job_status)).await;
+
+        let get = |uri: String| TestRequest::get().uri(&uri).to_request();
+        let response = call_service(&app, get(format!("/jobs/{}", id))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call_service(&app, get("/jobs/unknown".to_owned())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["error"], "unknown job");
+    }
+}
@@ -22,14 +22,34 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-use git2::{build::RepoBuilder, Cred, Direction, FetchOptions, RemoteCallbacks, Repository};
+mod admin;
+mod api;
+mod blob_cache;
+mod cache;
+mod charts;
+mod diff;
+mod disk_cache;
+mod filters;
+mod history;
+mod jobs;
+mod metrics;
+mod mirrors;
+mod pool;
+mod repo_config;
+mod single_flight;
+mod tree_count;
+mod watch;
+mod webhooks;
+
+use git2::{Cred, Direction, FetchOptions, RemoteCallbacks, Repository};
 use std::path::Path;
 
 use actix_web::{
     get,
     http::header::{
-        Accept, CacheControl, CacheDirective, ContentType, EntityTag, Header, IfNoneMatch,
-        CACHE_CONTROL, CONTENT_TYPE, ETAG, LOCATION,
+        Accept, CacheControl, CacheDirective, ContentType, EntityTag, Header, HeaderName,
+        HeaderValue, IfNoneMatch, CACHE_CONTROL, CONTENT_TYPE, ETAG, LOCATION, RETRY_AFTER,
+        WARNING,
     },
     web::{self},
     App, HttpRequest, HttpResponse, HttpServer,
@@ -91,6 +111,90 @@ struct Args {
         default_value = "gfs,xsd,csv,dxf,wkt,dgn,rsc,png,a,so,pc,ai,jpg,gif,gz,bz2,xz,gzip,bzip2,pdf"
     )]
     ignore_filetype: String,
+    /// Count hidden files and directories by default (tokei `hidden`).
+    /// Fallback environment variable: TOKEI_HIDDEN.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    hidden: Option<bool>,
+    /// Don't respect ignore files (.gitignore, .ignore, .tokeignore, etc.) by
+    /// default (tokei `no_ignore`). Fallback environment variable: TOKEI_NO_IGNORE.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_ignore: Option<bool>,
+    /// Don't respect ignore files in parent directories by default (tokei
+    /// `no_ignore_parent`). Fallback environment variable: TOKEI_NO_IGNORE_PARENT.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_ignore_parent: Option<bool>,
+    /// Don't respect VCS ignore files (.gitignore, etc.) by default (tokei
+    /// `no_ignore_vcs`). Fallback environment variable: TOKEI_NO_IGNORE_VCS.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    no_ignore_vcs: Option<bool>,
+    /// Count doc strings (e.g. Python docstrings) as comments by default (tokei
+    /// `treat_doc_strings_as_comments`). Fallback environment variable:
+    /// TOKEI_TREAT_DOC_STRINGS_AS_COMMENTS.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    treat_doc_strings_as_comments: Option<bool>,
+    /// Maximum number of repositories listed, cloned or counted at the same
+    /// time. Fallback environment variable: TOKEI_MAX_CONCURRENT_CLONES.
+    #[arg(long, default_value_t = 4usize)]
+    max_concurrent_clones: usize,
+    /// Maximum number of requests waiting for a clone slot; further requests
+    /// get a "server busy" badge. Fallback environment variable: TOKEI_CLONE_QUEUE_SIZE.
+    #[arg(long, default_value_t = 64usize)]
+    clone_queue_size: usize,
+    /// Comma-separated list of language types counted by default (tokei `types`),
+    /// e.g. "Rust,Python". All languages are counted when unset.
+    /// Fallback environment variable: TOKEI_TYPES.
+    #[arg(long)]
+    types: Option<String>,
+    /// Directory holding a persistent copy of the statistics cache, so that
+    /// it survives restarts. Disabled when unset.
+    /// Fallback environment variable: TOKEI_CACHE_DIR.
+    #[arg(long)]
+    cache_dir: Option<String>,
+    /// Where computed statistics are kept: `memory` (this process only) or
+    /// `redis`, shared by every replica pointing at the same server.
+    /// Fallback environment variable: TOKEI_CACHE_BACKEND.
+    #[arg(long, default_value = "memory")]
+    cache_backend: String,
+    /// Server used by the `redis` cache backend.
+    /// Fallback environment variable: TOKEI_REDIS_URL.
+    #[arg(long, default_value = "redis://127.0.0.1:6379")]
+    redis_url: String,
+    /// Bearer token required by the `/admin/v1/...` endpoints, which are
+    /// disabled when unset. Fallback environment variable: TOKEI_ADMIN_TOKEN.
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Secret shared with the git servers calling the `/hooks/...` push
+    /// webhooks, which are disabled when unset.
+    /// Fallback environment variable: TOKEI_WEBHOOK_SECRET.
+    #[arg(long)]
+    webhook_secret: Option<String>,
+    /// TOML file listing repositories whose statistics are kept precomputed.
+    /// Fallback environment variable: TOKEI_WATCH_LIST.
+    #[arg(long)]
+    watch_list: Option<String>,
+    /// Seconds between two checks of the watched repositories' heads.
+    /// Fallback environment variable: TOKEI_WATCH_INTERVAL.
+    #[arg(long, default_value_t = 300u64)]
+    watch_interval: u64,
+    /// Directory keeping a bare mirror of every counted repository, fetched
+    /// incrementally instead of cloning anew. Disabled when unset.
+    /// Fallback environment variable: TOKEI_MIRROR_DIR.
+    #[arg(long)]
+    mirror_dir: Option<String>,
+    /// Disk space the mirrors may use, in megabytes; the least recently used
+    /// ones are removed beyond it. Fallback environment variable: TOKEI_MIRROR_MAX_SIZE.
+    #[arg(long, default_value_t = 10240u64)]
+    mirror_max_size: u64,
+    /// Maximum number of files whose counts are kept, keyed by blob, so that
+    /// unchanged files aren't parsed again; 0 disables it.
+    /// Fallback environment variable: TOKEI_BLOB_CACHE_SIZE.
+    #[arg(long, default_value_t = 200_000usize)]
+    blob_cache_size: usize,
+    /// SQLite file recording the statistics of every counted commit, served
+    /// by `/api/v1/.../history`. Disabled when unset.
+    /// Fallback environment variable: TOKEI_HISTORY_DB.
+    #[arg(long)]
+    history_db: Option<String>,
 }
 // App configuration passed to handlers
 #[derive(Clone)]
@@ -98,18 +202,44 @@ struct AppConfig {
     user_whitelist: Option<std::collections::HashSet<String>>,
     gitserver_whitelist: Option<std::collections::HashSet<String>>,
     ignore_filetypes: Option<std::collections::HashSet<String>>,
+    /// tokei options applied when a request doesn't override them.
+    tokei_defaults: TokeiOptions,
+    /// Pool running the blocking git and tokei work.
+    workers: std::sync::Arc<pool::WorkerPool>,
+    /// Counts currently running, keyed like the statistics cache, so that
+    /// concurrent requests for the same commit share a single clone.
+    in_flight: std::sync::Arc<single_flight::SingleFlight<CountOutcome>>,
+    /// Storage of the computed statistics, see `--cache-backend`.
+    cache: std::sync::Arc<dyn cache::StatisticsCache>,
+    /// Persistent copy of the statistics, with `--cache-dir`.
+    disk_cache: Option<std::sync::Arc<disk_cache::DiskCache>>,
+    /// Token of the admin endpoints, see `--admin-token`.
+    admin_token: Option<String>,
+    /// Secret of the push webhooks, see `--webhook-secret`.
+    webhook_secret: Option<String>,
+    /// Asynchronous statistics jobs, by ID.
+    jobs: std::sync::Arc<jobs::Jobs>,
+    /// Mirrors of the counted repositories, with `--mirror-dir`.
+    mirrors: Option<std::sync::Arc<mirrors::Mirrors>>,
+    /// Statistics of every counted commit, with `--history-db`.
+    history: Option<std::sync::Arc<history::History>>,
 }
-use cached::{Cached, Return};
+use cached::Return;
 use csscolorparser::parse;
 use once_cell::sync::Lazy;
 use rsbadges::{Badge, Style};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use tempfile::TempDir;
-use tokei::{Language, LanguageType, Languages};
+use tokei::{Language, LanguageType};
 
 const BILLION: usize = 1_000_000_000;
 const BLANKS: &str = "blank lines";
 const BLUE: &str = "#007ec6";
+const BUSY: &str = "server busy";
+const COMPUTING: &str = "computing…";
+const BUSY_RETRY_AFTER_SECONDS: u32 = 5;
+/// Custom header flagging stale statistics, alongside the `Warning` header.
+const STALE_HEADER: &str = "x-tokei-stale";
 const GREY: &str = "#555555";
 const CODE: &str = "lines of code";
 const COMMENTS: &str = "comments";
@@ -119,11 +249,35 @@ const LINES: &str = "total lines";
 const MILLION: usize = 1_000_000;
 const THOUSAND: usize = 1_000;
 const DAY_IN_SECONDS: u64 = 24 * 60 * 60;
+/// Interval at which a replica checks whether the commit another replica is
+/// counting is done.
+const COUNT_LOCK_POLL: std::time::Duration = std::time::Duration::from_millis(500);
 
 static CONTENT_TYPE_SVG: Lazy<ContentType> =
     Lazy::new(|| ContentType("image/svg+xml".parse().unwrap()));
 static CACHE_TTL_SECONDS: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(DAY_IN_SECONDS));
 static CACHE_MAX_ENTRIES: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(1000));
+static BLOB_CACHE_MAX_ENTRIES: Lazy<AtomicUsize> = Lazy::new(|| AtomicUsize::new(200_000));
+/// Counts of the files seen in every counted commit, see `blob_cache`.
+static BLOBS: Lazy<blob_cache::BlobCache> =
+    Lazy::new(|| blob_cache::BlobCache::new(BLOB_CACHE_MAX_ENTRIES.load(Ordering::Relaxed)));
+/// Statistics counted by this process, keyed by `statistics_key`, when the
+/// cache backend is `memory`.
+static CACHE: Lazy<std::sync::Mutex<cached::TimedSizedCache<String, Return<Counts>>>> =
+    Lazy::new(|| {
+        std::sync::Mutex::new(cached::TimedSizedCache::with_size_and_lifespan(
+            CACHE_MAX_ENTRIES.load(Ordering::Relaxed),
+            std::time::Duration::from_secs(CACHE_TTL_SECONDS.load(Ordering::Relaxed)),
+        ))
+    });
+/// Secondary index of `CACHE`: the commit last counted for a reference and
+/// set of options, keyed by `latest_key`. Lets a moved branch be served its
+/// previous statistics while the new head is being counted.
+static LATEST: Lazy<std::sync::Mutex<cached::SizedCache<String, String>>> = Lazy::new(|| {
+    std::sync::Mutex::new(cached::SizedCache::with_size(
+        CACHE_MAX_ENTRIES.load(Ordering::Relaxed),
+    ))
+});
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -171,6 +325,56 @@ async fn main() -> std::io::Result<()> {
             }
         }
     }
+    if args.max_concurrent_clones == 4 {
+        if let Ok(env_max) = std::env::var("TOKEI_MAX_CONCURRENT_CLONES") {
+            if let Ok(parsed) = env_max.parse::<usize>() {
+                args.max_concurrent_clones = parsed;
+            }
+        }
+    }
+    if args.cache_backend == "memory" {
+        if let Ok(backend) = std::env::var("TOKEI_CACHE_BACKEND") {
+            if !backend.is_empty() {
+                args.cache_backend = backend.to_ascii_lowercase();
+            }
+        }
+    }
+    if args.redis_url == "redis://127.0.0.1:6379" {
+        if let Ok(url) = std::env::var("TOKEI_REDIS_URL") {
+            if !url.is_empty() {
+                args.redis_url = url;
+            }
+        }
+    }
+    if args.watch_interval == 300 {
+        if let Ok(env_interval) = std::env::var("TOKEI_WATCH_INTERVAL") {
+            if let Ok(parsed) = env_interval.parse::<u64>() {
+                args.watch_interval = parsed;
+            }
+        }
+    }
+    if args.mirror_max_size == 10240 {
+        if let Ok(env_size) = std::env::var("TOKEI_MIRROR_MAX_SIZE") {
+            if let Ok(parsed) = env_size.parse::<u64>() {
+                args.mirror_max_size = parsed;
+            }
+        }
+    }
+    if args.blob_cache_size == 200_000 {
+        if let Ok(env_size) = std::env::var("TOKEI_BLOB_CACHE_SIZE") {
+            if let Ok(parsed) = env_size.parse::<usize>() {
+                args.blob_cache_size = parsed;
+            }
+        }
+    }
+    BLOB_CACHE_MAX_ENTRIES.store(args.blob_cache_size, Ordering::Relaxed);
+    if args.clone_queue_size == 64 {
+        if let Ok(env_size) = std::env::var("TOKEI_CLONE_QUEUE_SIZE") {
+            if let Ok(parsed) = env_size.parse::<usize>() {
+                args.clone_queue_size = parsed;
+            }
+        }
+    }
     dotenv::dotenv().ok();
     // Configure logging: default to verbose (debug) unless disabled with `-q`
     // or overridden via the `RUST_LOG` environment variable. We parse
@@ -239,10 +443,163 @@ async fn main() -> std::io::Result<()> {
             )
         };
 
+    // tokei options: a flag (`--hidden`, `--hidden=false`) sets an option,
+    // its environment variable only applies when the flag is absent.
+    let flag = |value: Option<bool>, name: &str| {
+        value.unwrap_or_else(|| {
+            std::env::var(name)
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false)
+        })
+    };
+    let tokei_defaults = TokeiOptions {
+        hidden: flag(args.hidden, "TOKEI_HIDDEN"),
+        no_ignore: flag(args.no_ignore, "TOKEI_NO_IGNORE"),
+        no_ignore_parent: flag(args.no_ignore_parent, "TOKEI_NO_IGNORE_PARENT"),
+        no_ignore_vcs: flag(args.no_ignore_vcs, "TOKEI_NO_IGNORE_VCS"),
+        treat_doc_strings_as_comments: flag(
+            args.treat_doc_strings_as_comments,
+            "TOKEI_TREAT_DOC_STRINGS_AS_COMMENTS",
+        ),
+        types: parse_types(
+            &args
+                .types
+                .clone()
+                .or_else(|| std::env::var("TOKEI_TYPES").ok())
+                .unwrap_or_default(),
+        ),
+    };
+
+    let cache_dir = args
+        .cache_dir
+        .clone()
+        .or_else(|| std::env::var("TOKEI_CACHE_DIR").ok())
+        .filter(|dir| !dir.is_empty());
+    let disk_cache = match cache_dir {
+        Some(dir) => {
+            let cache =
+                disk_cache::DiskCache::open(Path::new(&dir), args.cache_ttl).map_err(|e| {
+                    log::error!("Unable to open the cache in {}: {}", dir, e);
+                    std::io::Error::other(e.to_string())
+                })?;
+            log::info!(
+                "Disk cache configured: {} ({} entries)",
+                dir,
+                cache.entries().unwrap_or(0)
+            );
+            Some(std::sync::Arc::new(cache))
+        }
+        None => None,
+    };
+
+    let mirror_dir = args
+        .mirror_dir
+        .clone()
+        .or_else(|| std::env::var("TOKEI_MIRROR_DIR").ok())
+        .filter(|dir| !dir.is_empty());
+    let mirrors = match mirror_dir {
+        Some(dir) => {
+            let mirrors =
+                mirrors::Mirrors::open(Path::new(&dir), args.mirror_max_size * 1024 * 1024)
+                    .map_err(|e| {
+                        log::error!("Unable to open the mirrors in {}: {}", dir, e);
+                        std::io::Error::other(e.to_string())
+                    })?;
+            let (count, size) = mirrors.usage();
+            log::info!(
+                "Mirror directory configured: {} ({} mirrors, {} MB of {} MB)",
+                dir,
+                count,
+                size / (1024 * 1024),
+                args.mirror_max_size
+            );
+            Some(std::sync::Arc::new(mirrors))
+        }
+        None => None,
+    };
+
+    let history_db = args
+        .history_db
+        .clone()
+        .or_else(|| std::env::var("TOKEI_HISTORY_DB").ok())
+        .filter(|path| !path.is_empty());
+    let history = match history_db {
+        Some(path) => {
+            let history = history::History::open(Path::new(&path)).map_err(|e| {
+                log::error!("Unable to open the history in {}: {}", path, e);
+                std::io::Error::other(e.to_string())
+            })?;
+            log::info!(
+                "History configured: {} ({} commits)",
+                path,
+                history.entries().unwrap_or(0)
+            );
+            Some(std::sync::Arc::new(history))
+        }
+        None => None,
+    };
+
+    let cache: std::sync::Arc<dyn cache::StatisticsCache> = match args.cache_backend.as_str() {
+        "memory" => std::sync::Arc::new(cache::MemoryCache::default()),
+        "redis" => std::sync::Arc::new(
+            cache::RedisCache::connect(&args.redis_url, args.cache_ttl)
+                .await
+                .map_err(|e| {
+                    log::error!("Unable to connect to {}: {}", args.redis_url, e);
+                    std::io::Error::other(e.to_string())
+                })?,
+        ),
+        backend => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown cache backend: {}", backend),
+            ))
+        }
+    };
+    log::info!("Cache backend configured: {}", cache.name());
+    log::info!("Blob cache size: {} files", args.blob_cache_size);
+
+    let watch_list = match args
+        .watch_list
+        .clone()
+        .or_else(|| std::env::var("TOKEI_WATCH_LIST").ok())
+        .filter(|path| !path.is_empty())
+    {
+        Some(path) => {
+            let list = watch::WatchList::load(Path::new(&path)).map_err(|e| {
+                log::error!("Unable to read the watch list {}: {}", path, e);
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+            })?;
+            Some((std::path::PathBuf::from(path), list))
+        }
+        None => None,
+    };
+
     let app_config = web::Data::new(AppConfig {
         user_whitelist: whitelist,
-        gitserver_whitelist: gitserver_whitelist,
-        ignore_filetypes: ignore_filetypes,
+        gitserver_whitelist,
+        ignore_filetypes,
+        tokei_defaults,
+        workers: std::sync::Arc::new(pool::WorkerPool::new(
+            args.max_concurrent_clones,
+            args.clone_queue_size,
+        )),
+        in_flight: std::sync::Arc::new(single_flight::SingleFlight::new()),
+        cache,
+        disk_cache,
+        admin_token: args
+            .admin_token
+            .clone()
+            .or_else(|| std::env::var("TOKEI_ADMIN_TOKEN").ok())
+            .filter(|token| !token.is_empty()),
+        webhook_secret: args
+            .webhook_secret
+            .clone()
+            .or_else(|| std::env::var("TOKEI_WEBHOOK_SECRET").ok())
+            .filter(|secret| !secret.is_empty()),
+        jobs: std::sync::Arc::new(jobs::Jobs::default()),
+        mirrors,
+        history,
     });
 
     // Inform administrators of whitelists at startup (if configured)
@@ -267,6 +624,35 @@ async fn main() -> std::io::Result<()> {
             log::info!("Ignore filetypes configured: {}", entries.join(","));
         }
     }
+    log::info!(
+        "Clone workers configured: {} concurrent, {} queued",
+        args.max_concurrent_clones.max(1),
+        args.clone_queue_size
+    );
+    if app_config.admin_token.is_some() {
+        log::info!("Admin API enabled");
+    }
+    if app_config.webhook_secret.is_some() {
+        log::info!("Push webhooks enabled");
+    }
+    if let Some((path, list)) = watch_list {
+        log::info!(
+            "Watch list configured: {} ({} repositories, every {}s)",
+            path.display(),
+            list.len(),
+            args.watch_interval.max(1)
+        );
+        actix_web::rt::spawn(watch::run(
+            app_config.clone(),
+            path,
+            list,
+            std::time::Duration::from_secs(args.watch_interval.max(1)),
+        ));
+    }
+    let tokei_identifier = app_config.tokei_defaults.identifier();
+    if !tokei_identifier.is_empty() {
+        log::info!("Default tokei options configured: {}", tokei_identifier);
+    }
 
     HttpServer::new(move || {
         App::new()
@@ -274,6 +660,21 @@ async fn main() -> std::io::Result<()> {
             .wrap(actix_web::middleware::Logger::default())
             .service(redirect_index)
             .service(create_badge)
+            .service(api::language_breakdown)
+            .service(diff::diff_breakdown)
+            .service(history::commit_history)
+            .service(jobs::create_job)
+            .service(jobs::job_status)
+            .service(charts::language_bar)
+            .service(charts::language_donut)
+            .service(diff::diff_badge)
+            .service(metrics::metrics)
+            .service(admin::list_cache)
+            .service(admin::purge_cache)
+            .service(admin::cache_stats)
+            .service(admin::warm_cache)
+            .service(admin::backfill_history)
+            .service(webhooks::scope())
     })
     .bind((args.bind.as_str(), args.port))?
     .run()
@@ -323,10 +724,250 @@ struct BadgeQuery {
     style: Option<String>,
     color: Option<String>,
     logo: Option<String>,
+    /// simple-icons slug of the shields.io format's logo; `logo` is an image.
+    #[serde(alias = "named_logo")]
+    named_logo: Option<String>,
     r#type: Option<String>,
     show_language: Option<String>,
     language_rank: Option<String>,
+    format: Option<String>,
+    #[serde(flatten)]
+    statistics: StatisticsQuery,
+}
+
+/// Output format of the badge endpoint.
+#[derive(Clone, Copy, PartialEq)]
+enum BadgeFormat {
+    Svg,
+    /// The summed tokei statistics (`Accept: application/json`).
+    Json,
+    /// shields.io's endpoint badge schema (`format=shields`).
+    Shields,
+}
+
+impl BadgeFormat {
+    /// Picks the format from the `format` query parameter, falling back to
+    /// the `Accept` header.
+    fn negotiate(request: &HttpRequest, format: Option<&str>) -> Self {
+        match format {
+            Some("svg") => return BadgeFormat::Svg,
+            Some("json") => return BadgeFormat::Json,
+            Some("shields") => return BadgeFormat::Shields,
+            _ => {}
+        }
+        match Accept::parse(request) {
+            Ok(accept) if accept == Accept::json() => BadgeFormat::Json,
+            _ => BadgeFormat::Svg,
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            BadgeFormat::Svg => CONTENT_TYPE_SVG.clone(),
+            BadgeFormat::Json | BadgeFormat::Shields => ContentType::json(),
+        }
+    }
+}
+
+/// Query parameters selecting which commit and which files get counted,
+/// shared by every endpoint serving repository statistics.
+#[derive(serde::Deserialize)]
+struct StatisticsQuery {
     branch: Option<String>,
+    tag: Option<String>,
+    sha: Option<String>,
+    path: Option<String>,
+    include: Option<String>,
+    exclude: Option<String>,
+    hidden: Option<String>,
+    no_ignore: Option<String>,
+    no_ignore_parent: Option<String>,
+    no_ignore_vcs: Option<String>,
+    treat_doc_strings_as_comments: Option<String>,
+    types: Option<String>,
+    embedded: Option<String>,
+    #[serde(rename = "async")]
+    run_async: Option<String>,
+}
+
+impl StatisticsQuery {
+    fn requested_ref(&self) -> actix_web::Result<RequestedRef> {
+        RequestedRef::from_query(self.branch.clone(), self.tag.clone(), self.sha.clone())
+    }
+
+    /// Whether statistics missing from the cache are counted by a job instead
+    /// of making the request wait, `false` by default.
+    fn is_async(&self) -> bool {
+        self.run_async
+            .as_deref()
+            .and_then(|v| v.parse::<bool>().ok())
+            .unwrap_or(false)
+    }
+
+    /// Whether code embedded in other languages' files is attributed to its
+    /// own language, `false` by default.
+    fn embedded(&self) -> bool {
+        self.embedded
+            .as_deref()
+            .and_then(|v| v.parse::<bool>().ok())
+            .unwrap_or(false)
+    }
+
+    /// Count options of the request, starting from the server-wide tokei
+    /// defaults. Boolean overrides that don't parse are ignored.
+    fn count_options(&self, defaults: &TokeiOptions) -> actix_web::Result<CountOptions> {
+        let flag = |value: &Option<String>, default: bool| {
+            value
+                .as_deref()
+                .and_then(|v| v.parse::<bool>().ok())
+                .unwrap_or(default)
+        };
+        Ok(CountOptions {
+            path: normalize_subdirectory(self.path.as_deref())?,
+            include: filters::parse_patterns(self.include.as_deref())?,
+            exclude: filters::parse_patterns(self.exclude.as_deref())?,
+            tokei: TokeiOptions {
+                hidden: flag(&self.hidden, defaults.hidden),
+                no_ignore: flag(&self.no_ignore, defaults.no_ignore),
+                no_ignore_parent: flag(&self.no_ignore_parent, defaults.no_ignore_parent),
+                no_ignore_vcs: flag(&self.no_ignore_vcs, defaults.no_ignore_vcs),
+                treat_doc_strings_as_comments: flag(
+                    &self.treat_doc_strings_as_comments,
+                    defaults.treat_doc_strings_as_comments,
+                ),
+                types: match &self.types {
+                    Some(types) => parse_types(types),
+                    None => defaults.types.clone(),
+                },
+            },
+        })
+    }
+}
+
+/// tokei settings controlling which files are walked and how they are parsed.
+#[derive(Clone, Default)]
+struct TokeiOptions {
+    hidden: bool,
+    no_ignore: bool,
+    no_ignore_parent: bool,
+    no_ignore_vcs: bool,
+    treat_doc_strings_as_comments: bool,
+    /// Only count these languages (all languages when empty).
+    types: Vec<LanguageType>,
+}
+
+impl TokeiOptions {
+    fn to_config(&self) -> tokei::Config {
+        tokei::Config {
+            hidden: Some(self.hidden),
+            no_ignore: Some(self.no_ignore),
+            no_ignore_parent: Some(self.no_ignore_parent),
+            no_ignore_vcs: Some(self.no_ignore_vcs),
+            treat_doc_strings_as_comments: Some(self.treat_doc_strings_as_comments),
+            types: (!self.types.is_empty()).then(|| self.types.clone()),
+            ..tokei::Config::default()
+        }
+    }
+
+    /// Suffix appended to cache keys and ETags, listing the enabled options.
+    fn identifier(&self) -> String {
+        let mut identifier = String::new();
+        for (enabled, name) in [
+            (self.hidden, "hidden"),
+            (self.no_ignore, "no_ignore"),
+            (self.no_ignore_parent, "no_ignore_parent"),
+            (self.no_ignore_vcs, "no_ignore_vcs"),
+            (
+                self.treat_doc_strings_as_comments,
+                "treat_doc_strings_as_comments",
+            ),
+        ] {
+            if enabled {
+                identifier.push('#');
+                identifier.push_str(name);
+            }
+        }
+        if !self.types.is_empty() {
+            let types: Vec<&str> = self.types.iter().map(|t| t.name()).collect();
+            identifier.push_str("#types=");
+            identifier.push_str(&types.join(","));
+        }
+        identifier
+    }
+}
+
+/// Parses a comma-separated list of language names into a sorted list of
+/// tokei language types, silently skipping unknown names.
+fn parse_types(types: &str) -> Vec<LanguageType> {
+    let mut types: Vec<LanguageType> = types
+        .split(',')
+        .filter_map(|s: &str| str::parse::<LanguageType>(s.trim()).ok())
+        .collect();
+    types.sort();
+    types.dedup();
+    types
+}
+
+/// Per-request options narrowing what `get_statistics` counts. Every option
+/// participates in the cache key and in the ETag.
+#[derive(Clone, Default)]
+struct CountOptions {
+    /// Subdirectory of the repository to count, relative to its root.
+    path: Option<String>,
+    /// Glob patterns a file must match to be counted (all files when empty).
+    include: Vec<String>,
+    /// Glob patterns of files left out of the count.
+    exclude: Vec<String>,
+    tokei: TokeiOptions,
+}
+
+impl CountOptions {
+    /// Suffix appended to cache keys and ETags, empty for default options so
+    /// that existing keys are unchanged.
+    fn identifier(&self) -> String {
+        let mut identifier = String::new();
+        if let Some(path) = &self.path {
+            identifier.push_str("#path=");
+            identifier.push_str(path);
+        }
+        if !self.include.is_empty() {
+            identifier.push_str("#include=");
+            identifier.push_str(&self.include.join(","));
+        }
+        if !self.exclude.is_empty() {
+            identifier.push_str("#exclude=");
+            identifier.push_str(&self.exclude.join(","));
+        }
+        identifier.push_str(&self.tokei.identifier());
+        identifier
+    }
+}
+
+/// Normalizes the `path` query parameter into a relative path made of plain
+/// components (no `..`, no leading `/`), or `None` for the repository root.
+/// Symbolic links are rejected when the path is looked up in the commit.
+fn normalize_subdirectory(path: Option<&str>) -> actix_web::Result<Option<String>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => {
+                return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                    "Invalid path provided."
+                )))
+            }
+            c if c.contains('\\') || c.contains('\0') => {
+                return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                    "Invalid path provided."
+                )))
+            }
+            c => components.push(c),
+        }
+    }
+    Ok((!components.is_empty()).then(|| components.join("/")))
 }
 
 #[get("/b1/{domain}/{user}/{repo}")]
@@ -338,197 +979,92 @@ async fn create_badge(
 ) -> actix_web::Result<HttpResponse> {
     let (domain, user, repo) = path.into_inner();
 
-    // If a whitelist is configured, ensure the requested user is allowed.
-    if let Some(whitelist) = &data.user_whitelist {
-        if !whitelist.contains(&user) {
-            log::warn!("User {} not in whitelist, returning forbidden badge", user);
-            // Return a red 'forbidden' badge (SVG) instead of HTTP 403 error.
-            let badge = make_badge_style("", "forbidden", "#e05d44", "plastic", "").await?;
-            return Ok(respond!(Forbidden, badge));
-        }
-    }
-    let category = query.category.unwrap_or_else(|| "lines".to_owned());
     let (label, no_label) = match query.label {
         Some(v) => (v, false),
         None => ("".to_owned(), true),
     };
     let style: String = query.style.unwrap_or_else(|| "plastic".to_owned());
     let color: String = query.color.unwrap_or_else(|| BLUE.to_owned());
-    let logo: String = query.logo.unwrap_or_else(|| "".to_owned());
-    let r#type: String = query.r#type.unwrap_or_else(|| "".to_owned());
+    let logo: String = query.logo.unwrap_or_default();
+    let named_logo: String = query.named_logo.unwrap_or_default();
+    let r#type: String = query.r#type.unwrap_or_default();
     let show_language: bool = query
         .show_language
-        .unwrap_or_else(|| "".to_owned())
+        .unwrap_or_default()
         .parse::<bool>()
         .unwrap_or(false);
     let language_rank: usize = match query.language_rank {
         Some(s) => s.parse::<usize>().unwrap_or(0),
         None => 1,
     };
-    let branch: String = query.branch.unwrap_or_else(|| "".to_owned());
-
-    let content_type: ContentType = if let Ok(accept) = Accept::parse(&request) {
-        if accept == Accept::json() {
-            ContentType::json()
-        } else {
-            CONTENT_TYPE_SVG.clone()
+    let format = BadgeFormat::negotiate(&request, query.format.as_deref());
+
+    let Statistics {
+        resolved,
+        etag,
+        languages,
+        default_category,
+        stale,
+        ..
+    } = match lookup_statistics(&request, &data, (domain, user, repo), &query.statistics).await? {
+        Lookup::Found(statistics) => *statistics,
+        Lookup::NotModified => return Ok(respond!(NotModified)),
+        Lookup::Forbidden if format == BadgeFormat::Shields => {
+            // shields.io only renders successful responses, flag the error in
+            // the body instead.
+            return Ok(HttpResponse::Ok().json(ShieldsEndpoint {
+                schema_version: 1,
+                label: "",
+                message: "forbidden",
+                color: "e05d44",
+                named_logo: "",
+                style: "plastic",
+                is_error: true,
+            }));
         }
-    } else {
-        CONTENT_TYPE_SVG.clone()
-    };
-
-    let mut domain = percent_encoding::percent_decode_str(&domain).decode_utf8()?;
-
-    // For backwards compatibility if a domain isn't specified we append `.com`.
-    if !domain.contains('.') {
-        domain += ".com";
-    }
-
-    // If a gitserver whitelist is configured, ensure the requested domain is allowed.
-    // We normalize the domain to lowercase to compare against the normalized whitelist.
-    let domain_lc = domain.to_ascii_lowercase();
-    if let Some(gsw) = &data.gitserver_whitelist {
-        if !gsw.contains(domain_lc.as_str()) {
-            log::warn!(
-                "Git server {} not in gitserver whitelist, returning forbidden badge",
-                domain
-            );
+        Lookup::Forbidden => {
+            // Return a red 'forbidden' badge (SVG) instead of HTTP 403 error.
             let badge = make_badge_style("", "forbidden", "#e05d44", "plastic", "").await?;
             return Ok(respond!(Forbidden, badge));
         }
-    }
-
-    let url: &str = &format!("https://{}/{}/{}", domain_lc, user, repo);
-
-    // Use libgit2 via git2 crate to query remote refs and determine branch
-    let tmp_bare_dir = TempDir::new()?;
-    let repo = match Repository::init_bare(tmp_bare_dir.path()) {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(actix_web::error::ErrorBadRequest(
-                eyre::eyre!(e.to_string()),
-            ))
-        }
-    };
-    let mut remote = match repo.remote_anonymous(&url) {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(actix_web::error::ErrorBadRequest(
-                eyre::eyre!(e.to_string()),
-            ))
-        }
-    };
-    if let Err(e) = remote.connect(Direction::Fetch) {
-        return Err(actix_web::error::ErrorBadRequest(
-            eyre::eyre!(e.to_string()),
-        ));
-    }
-    let refs = match remote.list() {
-        Ok(r) => r,
-        Err(e) => {
-            return Err(actix_web::error::ErrorBadRequest(
-                eyre::eyre!(e.to_string()),
-            ))
-        }
-    };
-
-    // Build a vector of available branch names (refs/heads/*)
-    let available_branches: Vec<String> = refs
-        .iter()
-        .filter_map(|r| {
-            let name = r.name();
-            if name.starts_with("refs/heads/") {
-                Some(name[11..].to_string())
-            } else {
-                None
-            }
-        })
-        .collect();
-    if available_branches.is_empty() {
-        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
-            "Invalid SHA provided."
-        )));
-    }
-
-    // Determine default head branch if not provided by query:
-    // prefer 'main' then 'master' then the first branch
-    let head_branch = if available_branches.contains(&"main".to_string()) {
-        "main".to_string()
-    } else if available_branches.contains(&"master".to_string()) {
-        "master".to_string()
-    } else {
-        available_branches[0].clone()
-    };
-
-    // If the request included a `branch` verify it's available
-    if !branch.is_empty() && !available_branches.contains(&branch) {
-        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
-            "Invalid SHA provided."
-        )));
-    }
-
-    let branch_name = if branch.is_empty() {
-        head_branch.as_str()
-    } else {
-        &branch
-    };
-    // Find the oid for the requested branch
-    let mut sha: String = String::new();
-    let target_ref = format!("refs/heads/{}", branch_name);
-    for r in refs.iter() {
-        if r.name() == target_ref.as_str() {
-            sha = r.oid().to_string();
-            break;
+        Lookup::Busy if format == BadgeFormat::Shields => {
+            return Ok(HttpResponse::Ok().json(ShieldsEndpoint {
+                schema_version: 1,
+                label: "",
+                message: BUSY,
+                color: "e05d44",
+                named_logo: "",
+                style: "plastic",
+                is_error: true,
+            }));
         }
-    }
-    (sha.len() == HASH_LENGTH)
-        .then(|| ())
-        .ok_or_else(|| actix_web::error::ErrorBadRequest(eyre::eyre!("Invalid SHA provided.")))?;
-
-    if let Ok(if_none_match) = IfNoneMatch::parse(&request) {
-        log::debug!("Checking If-None-Match: {}#{}", sha, branch_name);
-        let entity_tag: EntityTag = EntityTag::new(false, etag_identifier(&sha, branch_name));
-        let found_match: bool = match if_none_match {
-            IfNoneMatch::Any => false,
-            IfNoneMatch::Items(items) => items
-                .iter()
-                .any(|etag: &EntityTag| etag.weak_eq(&entity_tag)),
-        };
-
-        if found_match {
-            CACHE
-                .lock()
-                .unwrap()
-                .cache_get(&repo_identifier(&url, &sha, branch_name));
-            log::info!("{}#{}#{} Not Modified", url, sha, branch_name);
-            return Ok(respond!(NotModified));
+        Lookup::Busy => return busy_response().await,
+        Lookup::Pending(id) => {
+            return match format {
+                BadgeFormat::Svg => computing_response(&id).await,
+                BadgeFormat::Json => Ok(jobs::accepted(&data, &id)),
+                // shields.io only renders successful responses.
+                BadgeFormat::Shields => Ok(HttpResponse::Ok().json(ShieldsEndpoint {
+                    schema_version: 1,
+                    label: "",
+                    message: COMPUTING,
+                    color: "lightgrey",
+                    named_logo: "",
+                    style: "plastic",
+                    is_error: false,
+                })),
+            };
         }
-    }
-
-    let entry: Return<Vec<(LanguageType, Language)>> =
-        get_statistics(&url, &sha, &branch_name, data.ignore_filetypes.as_ref())
-            .map_err(actix_web::error::ErrorBadRequest)?;
-
-    if entry.was_cached {
-        log::info!("{}#{}#{} Cache hit", url, sha, branch_name);
-    }
-
-    let language_types: HashSet<LanguageType> = r#type
-        .split(',')
-        .filter_map(|s: &str| str::parse::<LanguageType>(s).ok())
-        .into_iter()
-        .collect::<HashSet<LanguageType>>();
-
-    let languages: Vec<(LanguageType, Language)> = if language_types.is_empty() {
-        entry.value
-    } else {
-        entry
-            .value
-            .into_iter()
-            .filter(|(language_type, _)| language_types.contains(&language_type))
-            .into_iter()
-            .collect()
     };
+    let reference = resolved.reference();
+    let (url, sha, reference): (&str, &str, &str) = (&resolved.url, &resolved.sha, &reference);
+    // An explicit category wins over the one set by the repository.
+    let category = query
+        .category
+        .or(default_category)
+        .unwrap_or_else(|| "lines".to_owned());
+
+    let languages = filter_language_types(languages, &r#type);
     let ranking_language = if !show_language {
         String::new()
     } else if languages.is_empty() {
@@ -546,10 +1082,10 @@ async fn create_badge(
     }
 
     log::debug!(
-        "{url}#{sha}#{branch_name} - Languages (most common to least common) {languages:#?} Lines {lines} Code {code} Comments {comments} Blanks {blanks}",
+        "{url}#{sha}#{reference} - Languages (most common to least common) {languages:#?} Lines {lines} Code {code} Comments {comments} Blanks {blanks}",
         url = url,
         sha = sha,
-        branch_name = branch_name,
+        reference = reference,
         languages = languages,
         lines = stats.lines(),
         code = stats.code,
@@ -561,7 +1097,7 @@ async fn create_badge(
         "{}#{}#{} - Lines: {} Code: {} Comments: {} Blanks: {}",
         url,
         sha,
-        branch_name,
+        reference,
         stats.lines(),
         stats.code,
         stats.comments,
@@ -569,117 +1105,1302 @@ async fn create_badge(
     );
 
     let badge: String = make_badge(
-        &content_type,
+        format,
+        &resolved,
+        stale,
         &stats,
         &category,
         &label,
         &style,
         &color,
         &logo,
+        &named_logo,
         &ranking_language,
         no_label,
     )
     .await?;
 
-    Ok(respond!(
-        Ok,
-        content_type,
-        badge,
-        etag_identifier(&sha, branch_name)
-    ))
+    let mut response = respond!(Ok, format.content_type(), badge, etag);
+    if stale {
+        mark_stale(&mut response);
+    }
+    Ok(response)
 }
 
-fn repo_identifier(url: &str, sha: &str, branch_name: &str) -> String {
-    format!("{}#{}#{}", url, sha, branch_name)
+/// Builds the red 'server busy' badge returned when the clone queue is full,
+/// asking clients to retry after a few seconds.
+async fn busy_response() -> actix_web::Result<HttpResponse> {
+    let badge = make_badge_style("", BUSY, "#e05d44", "plastic", "").await?;
+    Ok(HttpResponse::ServiceUnavailable()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((RETRY_AFTER, BUSY_RETRY_AFTER_SECONDS))
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(badge))
 }
 
-fn etag_identifier(sha: &str, branch_name: &str) -> String {
-    format!("{}#{}", sha, branch_name)
+/// Builds the grey 'computing…' placeholder badge returned while a job
+/// counts the statistics, pointing at the job.
+async fn computing_response(id: &str) -> actix_web::Result<HttpResponse> {
+    let badge = make_badge_style("", COMPUTING, "#9f9f9f", "plastic", "").await?;
+    Ok(HttpResponse::Accepted()
+        .insert_header((CACHE_CONTROL, CacheControl(vec![CacheDirective::NoCache])))
+        .insert_header((LOCATION, format!("/jobs/{}", id)))
+        .insert_header((RETRY_AFTER, jobs::POLL_SECONDS))
+        .insert_header((CONTENT_TYPE, CONTENT_TYPE_SVG.clone()))
+        .body(badge))
 }
 
-#[cached::proc_macro::cached(
-    name = "CACHE",
-    result = true,
-    with_cached_flag = true,
-    ty = "cached::TimedSizedCache<String, cached::Return<Vec<(LanguageType,Language)>>>",
-    create = r#"{ let ttl = CACHE_TTL_SECONDS.load(Ordering::Relaxed); let max = CACHE_MAX_ENTRIES.load(Ordering::Relaxed); cached::TimedSizedCache::with_size_and_lifespan(max, std::time::Duration::from_secs(ttl)) }"#,
-    convert = r#"{ let mut key = repo_identifier(url, _sha, branch_name); if let Some(ifts) = ignore_filetypes { let mut v: Vec<String> = ifts.iter().cloned().collect(); v.sort(); if !v.is_empty() { key.push('#'); key.push_str(&v.join(",")); } } key }"#
-)]
-fn get_statistics(
-    url: &str,
-    _sha: &str,
-    branch_name: &str,
-    ignore_filetypes: Option<&std::collections::HashSet<String>>,
-) -> eyre::Result<cached::Return<Vec<(LanguageType, Language)>>> {
-    log::info!("{} - Cloning", url);
-    let temp_dir: TempDir = TempDir::new()?;
-    let temp_path: &str = temp_dir.path().to_str().unwrap();
+/// Flags a response built from stale statistics, see `Statistics::stale`.
+fn mark_stale(response: &mut HttpResponse) {
+    let headers = response.headers_mut();
+    headers.insert(
+        WARNING,
+        HeaderValue::from_static("110 tokeisrv \"Response is Stale\""),
+    );
+    headers.insert(
+        HeaderName::from_static(STALE_HEADER),
+        HeaderValue::from_static("true"),
+    );
+}
 
-    // Clone using libgit2 RepoBuilder with shallow depth and optional credentials
-    let mut fo = FetchOptions::new();
-    let mut callbacks = RemoteCallbacks::new();
-    // Use GITHUB_TOKEN if available for HTTPS auth (x-access-token)
-    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
-        callbacks.credentials(move |_, _username_from_url, _| {
-            // Use username "x-access-token" as suggested by GitHub for personal access tokens
-            Cred::userpass_plaintext("x-access-token", &token)
-        });
-    }
-    fo.remote_callbacks(callbacks);
-    fo.depth(1);
+/// Outcome of `lookup_statistics`.
+enum Lookup {
+    /// Rejected by one of the configured whitelists.
+    Forbidden,
+    /// The client's `If-None-Match` already matches the resolved commit.
+    NotModified,
+    /// The clone queue is full, the client should retry later.
+    Busy,
+    /// The statistics weren't cached and are being counted by this job, as
+    /// requested with `async=true`.
+    Pending(String),
+    Found(Box<Statistics>),
+}
+
+/// Statistics of a resolved commit, as returned by `lookup_statistics`.
+struct Statistics {
+    resolved: ResolvedRepository,
+    options: CountOptions,
+    etag: String,
+    /// Languages, most lines of code first.
+    languages: Vec<(LanguageType, Language)>,
+    /// Badge category set by the repository's `.tokeisrv.toml`.
+    default_category: Option<String>,
+    /// Code embedded in other languages' files by language, already included
+    /// in `languages`. Empty unless the request asked for `embedded=true`.
+    embedded: BTreeMap<LanguageType, Language>,
+    /// The reference moved on and `resolved.sha` is the commit previously
+    /// counted for it; fresh statistics are being computed in the background.
+    stale: bool,
+}
 
-    let mut builder = RepoBuilder::new();
-    builder.fetch_options(fo);
-    if !branch_name.is_empty() {
-        builder.branch(branch_name);
+/// Result of counting a commit, as stored in the statistics cache.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct Counts {
+    /// Languages, most lines of code first.
+    languages: Vec<(LanguageType, Language)>,
+    /// Badge category set by the repository's `.tokeisrv.toml`.
+    default_category: Option<String>,
+    /// Commit time, in seconds since the Unix epoch. Missing from statistics
+    /// cached by older versions.
+    #[serde(default)]
+    committed_at: Option<i64>,
+}
+
+/// Resolves the requested reference of `domain/user/repo`, honours the
+/// request's `If-None-Match` header and returns the (possibly cached)
+/// statistics of the resolved commit. Shared by the badge, chart and JSON API
+/// endpoints so that they all hit the same cache entries.
+///
+/// Listing the remote refs and counting a commit missing from the cache run
+/// on the bounded worker pool; `Lookup::Busy` is returned when its queue is
+/// full.
+async fn lookup_statistics(
+    request: &HttpRequest,
+    data: &web::Data<AppConfig>,
+    (domain, user, repo): (String, String, String),
+    query: &StatisticsQuery,
+) -> actix_web::Result<Lookup> {
+    let requested = query.requested_ref()?;
+    let options = query.count_options(&data.tokei_defaults)?;
+    let mut resolved = match resolve_repository(data, &domain, &user, &repo, &requested).await? {
+        Resolution::Resolved(resolved) => resolved,
+        Resolution::Forbidden => return Ok(Lookup::Forbidden),
+        Resolution::Busy => return Ok(Lookup::Busy),
+    };
+    let reference = resolved.reference();
+    let (url, sha, reference): (&str, &str, &str) = (&resolved.url, &resolved.sha, &reference);
+    let embedded = query.embedded();
+    let mut etag = etag_identifier(sha, reference, &options, embedded);
+
+    if etag_matches(request, &etag) {
+        data.cache
+            .touch(&statistics_key(
+                url,
+                sha,
+                reference,
+                data.ignore_filetypes.as_ref(),
+                &options,
+            ))
+            .await;
+        log::info!("{}#{}#{} Not Modified", url, sha, reference);
+        return Ok(Lookup::NotModified);
     }
-    builder
-        .clone(url, Path::new(temp_path))
-        .map_err(|e| eyre::eyre!(e.to_string()))?;
 
-    let mut languages: Languages = Languages::new();
-    log::info!("{} - Getting Statistics", url);
-    // Build a set of exclude patterns from configured ignore filetypes.
-    // Convert extension `foo` to glob pattern `**/*.foo`.
-    let mut exclude_patterns: Vec<String> = Vec::new();
-    if let Some(ifts) = ignore_filetypes {
-        for ext in ifts {
-            // ignore trailing dots or accidental leading dots
-            let normalized_ext = ext.trim().trim_start_matches('.');
-            if !normalized_ext.is_empty() {
-                exclude_patterns.push(format!("**/*.{}", normalized_ext));
-            }
+    let key = statistics_key(
+        url,
+        sha,
+        reference,
+        data.ignore_filetypes.as_ref(),
+        &options,
+    );
+    let cached: Option<Return<Counts>> = cache_get(data, &key).await.map(Return::new);
+    if cached.is_some() {
+        data.cache.stats().record_hit();
+    } else {
+        data.cache.stats().record_miss();
+    }
+    let latest = match cached {
+        Some(_) => None,
+        None => latest_statistics(data, url, sha, reference, &options).await,
+    };
+    let mut stale_sha: Option<String> = None;
+    let entry: Return<Counts> = match (cached, latest) {
+        (Some(mut entry), _) => {
+            entry.was_cached = true;
+            entry
         }
+        (None, Some((latest_sha, entry))) => {
+            // Stale while revalidate: the reference moved since it was last
+            // counted, serve its previous statistics right away.
+            log::info!(
+                "{}#{}#{} Serving stale statistics of {} while refreshing",
+                url,
+                sha,
+                reference,
+                latest_sha
+            );
+            refresh_in_background(data, key, url, sha, reference, &options);
+            stale_sha = Some(latest_sha);
+            entry
+        }
+        (None, None) if query.is_async() => {
+            let result = jobs::result_url(request);
+            let id = start_job(data, &resolved, key, &options, result).await;
+            return Ok(Lookup::Pending(id));
+        }
+        (None, None) => match count_coalesced(data, &key, url, sha, reference, &options).await? {
+            Some(entry) => entry,
+            None => return Ok(Lookup::Busy),
+        },
+    };
+
+    if entry.was_cached && stale_sha.is_none() {
+        log::info!("{}#{}#{} Cache hit", url, sha, reference);
     }
-    // Convert to slice of &str for tokei API
-    let exclude_refs: Vec<&str> = exclude_patterns.iter().map(|s| s.as_str()).collect();
-    languages.get_statistics(
-        &[temp_path],
-        if exclude_refs.is_empty() {
-            &[]
-        } else {
-            &exclude_refs[..]
+    let stale = stale_sha.is_some();
+    if let Some(stale_sha) = stale_sha {
+        etag = etag_identifier(&stale_sha, &resolved.reference(), &options, embedded);
+        resolved.sha = stale_sha;
+    }
+
+    let Counts {
+        mut languages,
+        default_category,
+        ..
+    } = entry.value;
+    let embedded = if embedded {
+        let embedded = embedded_statistics(&languages);
+        languages = attribute_embedded(languages, &embedded);
+        embedded
+    } else {
+        BTreeMap::new()
+    };
+
+    Ok(Lookup::Found(Box::new(Statistics {
+        resolved,
+        options,
+        etag,
+        languages,
+        default_category,
+        embedded,
+        stale,
+    })))
+}
+
+/// Counts a commit missing from the cache, sharing the count of concurrent
+/// requests for the same `key`. `None` when the clone queue is full.
+async fn count_coalesced(
+    data: &web::Data<AppConfig>,
+    key: &str,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    options: &CountOptions,
+) -> actix_web::Result<Option<Return<Counts>>> {
+    let (outcome, coalesced) = data
+        .in_flight
+        .run(key, || {
+            count_on_pool(data, key, url, sha, reference, options)
+        })
+        .await;
+    if coalesced {
+        log::info!(
+            "{}#{}#{} Coalesced with an in-flight analysis",
+            url,
+            sha,
+            reference
+        );
+    }
+    match outcome {
+        CountOutcome::Counted(entry) => Ok(Some(entry)),
+        CountOutcome::Busy => Ok(None),
+        CountOutcome::Invalid(message) => Err(actix_web::error::ErrorBadRequest(message)),
+        CountOutcome::Failed(message) => Err(actix_web::error::ErrorInternalServerError(message)),
+    }
+}
+
+/// Outcome of `warm_statistics`.
+enum Warming {
+    Forbidden,
+    Busy,
+    /// The statistics were already cached.
+    Cached(ResolvedRepository, String),
+    /// The statistics are being counted in the background.
+    Started(ResolvedRepository, String),
+}
+
+/// Resolves the requested reference of `domain/user/repo` and, unless its
+/// statistics are already cached, starts counting them in the background.
+/// The cache key of the statistics is returned along with the commit, see
+/// `warm_commit`.
+async fn warm_statistics(
+    data: &web::Data<AppConfig>,
+    (domain, user, repo): (String, String, String),
+    query: &StatisticsQuery,
+) -> actix_web::Result<Warming> {
+    let requested = query.requested_ref()?;
+    let options = query.count_options(&data.tokei_defaults)?;
+    let resolved = match resolve_repository(data, &domain, &user, &repo, &requested).await? {
+        Resolution::Resolved(resolved) => resolved,
+        Resolution::Forbidden => return Ok(Warming::Forbidden),
+        Resolution::Busy => return Ok(Warming::Busy),
+    };
+    Ok(match warm_commit(data, &resolved, &options).await {
+        (key, true) => Warming::Started(resolved, key),
+        (key, false) => Warming::Cached(resolved, key),
+    })
+}
+
+/// Starts counting the statistics of a resolved commit in the background,
+/// unless they are already cached. Returns their cache key, and whether they
+/// are being counted.
+async fn warm_commit(
+    data: &web::Data<AppConfig>,
+    resolved: &ResolvedRepository,
+    options: &CountOptions,
+) -> (String, bool) {
+    let reference = resolved.reference();
+    let key = statistics_key(
+        &resolved.url,
+        &resolved.sha,
+        &reference,
+        data.ignore_filetypes.as_ref(),
+        options,
+    );
+    if cache_get(data, &key).await.is_some() {
+        return (key, false);
+    }
+    log::info!(
+        "{}#{}#{} Warming up the cache",
+        resolved.url,
+        resolved.sha,
+        reference
+    );
+    refresh_in_background(
+        data,
+        key.clone(),
+        &resolved.url,
+        &resolved.sha,
+        &reference,
+        options,
+    );
+    (key, true)
+}
+
+/// Registers a job counting the statistics of a resolved commit and runs it
+/// in the background, unless a job is already counting them. Returns the job
+/// ID; the job is done right away when the statistics are cached.
+async fn start_job(
+    data: &web::Data<AppConfig>,
+    resolved: &ResolvedRepository,
+    key: String,
+    options: &CountOptions,
+    result: String,
+) -> String {
+    let (id, created) = data.jobs.create(&key, resolved, result);
+    if !created {
+        return id;
+    }
+    if let Some(counts) = cache_get(data, &key).await {
+        data.jobs
+            .finish(&id, &CountOutcome::Counted(cached_entry(counts)));
+        return id;
+    }
+    log::info!(
+        "{}#{}#{} Counting in job {}",
+        resolved.url,
+        resolved.sha,
+        resolved.reference(),
+        id
+    );
+    let data = data.clone();
+    let (url, sha, reference) = (
+        resolved.url.clone(),
+        resolved.sha.clone(),
+        resolved.reference(),
+    );
+    let options = options.clone();
+    let job = id.clone();
+    actix_web::rt::spawn(async move {
+        let (outcome, _) = data
+            .in_flight
+            .run(&key, || {
+                count_on_pool(&data, &key, &url, &sha, &reference, &options)
+            })
+            .await;
+        data.jobs.finish(&job, &outcome);
+    });
+    id
+}
+
+/// Statistics last counted for the same reference and options at another
+/// commit, along with that commit, if they are still cached. Requests pinned
+/// to a SHA never go stale.
+async fn latest_statistics(
+    data: &AppConfig,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    options: &CountOptions,
+) -> Option<(String, Return<Counts>)> {
+    if sha == reference {
+        return None;
+    }
+    let ignore_filetypes = data.ignore_filetypes.as_ref();
+    let latest_sha: String = match data
+        .cache
+        .latest(&latest_key(url, reference, ignore_filetypes, options))
+        .await
+    {
+        Ok(latest_sha) => latest_sha?,
+        Err(e) => {
+            log::warn!(
+                "{} - Unable to read from the {} cache: {}",
+                url,
+                data.cache.name(),
+                e
+            );
+            return None;
+        }
+    };
+    if latest_sha == sha {
+        return None;
+    }
+    let key = statistics_key(url, &latest_sha, reference, ignore_filetypes, options);
+    let entry = cache_get(data, &key).await?;
+    Some((latest_sha, Return::new(entry)))
+}
+
+/// Statistics stored under `key` by the cache backend. Errors of a remote
+/// backend are logged and count as a miss.
+async fn cache_get(data: &AppConfig, key: &str) -> Option<Counts> {
+    data.cache.get(key).await.unwrap_or_else(|e| {
+        log::warn!(
+            "{} - Unable to read from the {} cache: {}",
+            key,
+            data.cache.name(),
+            e
+        );
+        None
+    })
+}
+
+/// Stores counted statistics in the cache backend, and records `sha` as the
+/// latest commit of its reference when `latest_key` is set.
+async fn cache_set(
+    data: &AppConfig,
+    key: &str,
+    latest_key: Option<&str>,
+    sha: &str,
+    counts: &Counts,
+) {
+    let stored = match data.cache.set(key, counts).await {
+        Ok(()) => match latest_key {
+            Some(latest_key) => data.cache.set_latest(latest_key, sha).await,
+            None => Ok(()),
         },
-        &tokei::Config::default(),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        log::warn!(
+            "{} - Unable to write to the {} cache: {}",
+            key,
+            data.cache.name(),
+            e
+        );
+    }
+}
+
+/// Counts a commit without making the request wait for it. Coalesced with
+/// any request counting the same commit, so repeated stale hits only cause
+/// one refresh.
+fn refresh_in_background(
+    data: &web::Data<AppConfig>,
+    key: String,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    options: &CountOptions,
+) {
+    let data = data.clone();
+    let (url, sha, reference) = (url.to_owned(), sha.to_owned(), reference.to_owned());
+    let options = options.clone();
+    actix_web::rt::spawn(async move {
+        let (outcome, _) = data
+            .in_flight
+            .run(&key, || {
+                count_on_pool(&data, &key, &url, &sha, &reference, &options)
+            })
+            .await;
+        if let CountOutcome::Counted(_) = outcome {
+            log::info!("{}#{}#{} Refreshed in the background", url, sha, reference);
+        }
+    });
+}
+
+/// Outcome of counting a commit on the worker pool, shared by the requests
+/// coalesced on it.
+#[derive(Clone)]
+enum CountOutcome {
+    Counted(Return<Counts>),
+    /// The clone queue was full.
+    Busy,
+    /// The commit couldn't be fetched or counted (e.g. an invalid `path`).
+    Invalid(String),
+    /// The count crashed.
+    Failed(String),
+}
+
+/// Clones and counts a commit on the worker pool, storing the result in the
+/// statistics cache under `key`. The cache isn't locked while counting.
+///
+/// With `--cache-dir` the disk cache is looked up first, and counted commits
+/// are written to it. With a shared cache backend, the commit isn't counted
+/// while another replica is already counting it: its result is waited for.
+///
+/// Once the count lock is taken, the count runs in a task of its own, so that
+/// its result is still stored and the lock released if the request waiting
+/// for it is cancelled.
+async fn count_on_pool(
+    data: &web::Data<AppConfig>,
+    key: &str,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    options: &CountOptions,
+) -> CountOutcome {
+    if let Some(counts) = load_from_disk(data, key).await {
+        log::info!("{}#{}#{} Disk cache hit", url, sha, reference);
+        let latest_key = advancing_latest_key(data, url, sha, reference, options, &counts).await;
+        cache_set(data, key, latest_key.as_deref(), sha, &counts).await;
+        return CountOutcome::Counted(cached_entry(counts));
+    }
+    let lock = match wait_for_count_lock(data, key).await {
+        Locking::Counted(counts) => {
+            log::info!("{}#{}#{} Counted by another replica", url, sha, reference);
+            return CountOutcome::Counted(cached_entry(counts));
+        }
+        Locking::Locked(lock) => lock,
+    };
+
+    data.jobs.set_phase(key, jobs::Phase::Queued);
+    let data = data.clone();
+    let (key, url, sha, reference) = (
+        key.to_owned(),
+        url.to_owned(),
+        sha.to_owned(),
+        reference.to_owned(),
     );
+    let options = options.clone();
+    let job = (url.clone(), sha.clone(), reference.clone());
+    let ignore_filetypes = data.ignore_filetypes.clone();
+    let count_options = options.clone();
+    let disk_cache = data.disk_cache.clone();
+    let disk_key = key.clone();
+    let jobs = data.jobs.clone();
+    let mirrors = data.mirrors.clone();
+    let history = data.history.clone();
+    let history_options = options_identifier(ignore_filetypes.as_ref(), &options);
+    let task = actix_web::rt::spawn(async move {
+        let counted = data
+            .workers
+            .run(move || {
+                let (url, sha, reference) = job;
+                let counted = get_statistics(
+                    &url,
+                    &sha,
+                    &reference,
+                    ignore_filetypes.as_ref(),
+                    &count_options,
+                    mirrors.as_deref(),
+                    &|phase| jobs.set_phase(&disk_key, phase),
+                )?;
+                if let Some(disk_cache) = disk_cache {
+                    if let Err(e) = disk_cache.set(&disk_key, &counted.value) {
+                        log::warn!("{} - Unable to write to the disk cache: {}", url, e);
+                    }
+                }
+                if let Some(history) = history {
+                    if let Err(e) =
+                        history.record(&url, &history_options, &sha, &reference, &counted.value)
+                    {
+                        log::warn!("{} - Unable to record the history: {}", url, e);
+                    }
+                }
+                eyre::Ok(counted)
+            })
+            .await;
+        let outcome = match pool_outcome(counted, &url, &data.workers) {
+            Ok(Some(Ok(entry))) => {
+                let latest_key =
+                    advancing_latest_key(&data, &url, &sha, &reference, &options, &entry.value)
+                        .await;
+                cache_set(&data, &key, latest_key.as_deref(), &sha, &entry.value).await;
+                CountOutcome::Counted(entry)
+            }
+            Ok(Some(Err(e))) => CountOutcome::Invalid(e.to_string()),
+            Ok(None) => CountOutcome::Busy,
+            Err(e) => CountOutcome::Failed(e.to_string()),
+        };
+        data.jobs.count_finished(&key);
+        drop(lock);
+        outcome
+    });
+    task.await
+        .unwrap_or_else(|e| CountOutcome::Failed(e.to_string()))
+}
+
+/// The `latest_key` under which counted statistics of `sha` are recorded as
+/// the latest of their reference, `None` for a SHA request or when the latest
+/// commit recorded is newer: a slow count of an older commit finishing last
+/// mustn't make stale-while-revalidate serve it again.
+async fn advancing_latest_key(
+    data: &AppConfig,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    options: &CountOptions,
+    counts: &Counts,
+) -> Option<String> {
+    if sha == reference {
+        return None;
+    }
+    let ignore_filetypes = data.ignore_filetypes.as_ref();
+    let latest_key = latest_key(url, reference, ignore_filetypes, options);
+    let Some(committed_at) = counts.committed_at else {
+        return Some(latest_key);
+    };
+    let latest_sha = match data.cache.latest(&latest_key).await {
+        Ok(Some(latest_sha)) if latest_sha != sha => latest_sha,
+        _ => return Some(latest_key),
+    };
+    let latest = statistics_key(url, &latest_sha, reference, ignore_filetypes, options);
+    match cache_get(data, &latest)
+        .await
+        .and_then(|latest| latest.committed_at)
+    {
+        Some(latest_committed_at) if latest_committed_at > committed_at => {
+            log::info!(
+                "{}#{}#{} Keeping the newer {} as the latest commit",
+                url,
+                sha,
+                reference,
+                latest_sha
+            );
+            None
+        }
+        _ => Some(latest_key),
+    }
+}
+
+/// A cache entry flagged as such, for statistics found in a cache tier.
+fn cached_entry(counts: Counts) -> Return<Counts> {
+    let mut entry = Return::new(counts);
+    entry.was_cached = true;
+    entry
+}
+
+/// Outcome of `wait_for_count_lock`.
+enum Locking {
+    /// Another replica counted the commit.
+    Counted(Counts),
+    /// The commit is to be counted, holding the lock unless it couldn't be
+    /// taken.
+    Locked(Option<CountLock>),
+}
+
+/// Count lock of a key in the cache backend, released when dropped (even by a
+/// cancelled request).
+struct CountLock {
+    data: web::Data<AppConfig>,
+    key: String,
+}
 
-    let mut iter = languages.iter_mut();
-    while let Some((_, language)) = iter.next() {
-        for report in &mut language.reports {
-            report.name = report.name.strip_prefix(temp_path)?.to_owned();
+impl Drop for CountLock {
+    fn drop(&mut self) {
+        let data = self.data.clone();
+        let key = std::mem::take(&mut self.key);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = data.cache.unlock(&key).await {
+                log::warn!("{} - Unable to release the count lock: {}", key, e);
+            }
+        });
+    }
+}
+
+/// Takes the count lock of `key` in the cache backend. While another replica
+/// holds it, polls the cache and returns the statistics it stores there.
+/// Lock errors are logged and the commit is counted anyway.
+async fn wait_for_count_lock(data: &web::Data<AppConfig>, key: &str) -> Locking {
+    loop {
+        match data.cache.try_lock(key).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                log::warn!("{} - Unable to take the count lock: {}", key, e);
+                return Locking::Locked(None);
+            }
         }
-        for (_, child) in &mut language.children {
-            for language in child.into_iter() {
-                language.name = language.name.strip_prefix(temp_path)?.to_owned();
+        if let Some(counts) = cache_get(data, key).await {
+            return Locking::Counted(counts);
+        }
+        actix_web::rt::time::sleep(COUNT_LOCK_POLL).await;
+    }
+    let lock = CountLock {
+        data: data.clone(),
+        key: key.to_owned(),
+    };
+    // The replica holding the lock may have released it right after storing
+    // its result.
+    match cache_get(data, key).await {
+        Some(counts) => Locking::Counted(counts),
+        None => Locking::Locked(Some(lock)),
+    }
+}
+
+/// Reads the statistics stored under `key` in the disk cache, if any. Read
+/// errors count as a miss.
+async fn load_from_disk(data: &AppConfig, key: &str) -> Option<Counts> {
+    let disk_cache = data.disk_cache.clone()?;
+    let key = key.to_owned();
+    web::block(move || {
+        disk_cache.get(&key).unwrap_or_else(|e| {
+            log::warn!("{} - Unable to read from the disk cache: {}", key, e);
+            None
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Unwraps the result of a worker pool job, `None` when the queue was full.
+fn pool_outcome<T>(
+    outcome: Result<T, pool::PoolError>,
+    url: &str,
+    workers: &pool::WorkerPool,
+) -> actix_web::Result<Option<T>> {
+    match outcome {
+        Ok(value) => Ok(Some(value)),
+        Err(pool::PoolError::QueueFull) => {
+            log::warn!(
+                "{} - Clone queue full ({} waiting), rejecting request",
+                url,
+                workers.queued()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(actix_web::error::ErrorInternalServerError(e.to_string())),
+    }
+}
+
+/// Sums the code embedded in other languages' files (e.g. Rust blocks in
+/// Markdown, CSS in HTML) by the language it is written in. Nested blocks are
+/// folded into their enclosing block.
+fn embedded_statistics(languages: &[(LanguageType, Language)]) -> BTreeMap<LanguageType, Language> {
+    let mut embedded: BTreeMap<LanguageType, Language> = BTreeMap::new();
+    for (_, language) in languages {
+        for (language_type, reports) in &language.children {
+            let total = embedded.entry(*language_type).or_default();
+            for stats in reports.iter().map(|report| report.stats.summarise()) {
+                total.code += stats.code;
+                total.comments += stats.comments;
+                total.blanks += stats.blanks;
+            }
+        }
+    }
+    embedded
+}
+
+/// Adds embedded code to the lines of its language (but not to its files),
+/// dropping the now attributed children. Languages only found embedded are
+/// added, and the result is sorted by lines of code again.
+fn attribute_embedded(
+    languages: Vec<(LanguageType, Language)>,
+    embedded: &BTreeMap<LanguageType, Language>,
+) -> Vec<(LanguageType, Language)> {
+    let mut attributed: BTreeMap<LanguageType, Language> = languages.into_iter().collect();
+    for language in attributed.values_mut() {
+        language.children.clear();
+    }
+    for (language_type, stats) in embedded {
+        *attributed.entry(*language_type).or_default() += stats.clone();
+    }
+    let mut attributed: Vec<(LanguageType, Language)> = attributed.into_iter().collect();
+    attributed.sort_by_key(|(_, language)| std::cmp::Reverse(language.code));
+    attributed
+}
+
+/// The git reference a request asks to analyze, taken from the mutually
+/// exclusive `branch`, `tag` and `sha` query parameters.
+enum RequestedRef {
+    /// No reference given: use the remote's default head branch.
+    Default,
+    Branch(String),
+    Tag(String),
+    Sha(String),
+}
+
+impl RequestedRef {
+    fn from_query(
+        branch: Option<String>,
+        tag: Option<String>,
+        sha: Option<String>,
+    ) -> actix_web::Result<Self> {
+        let non_empty = |v: Option<String>| v.filter(|v| !v.is_empty());
+        match (non_empty(branch), non_empty(tag), non_empty(sha)) {
+            (None, None, None) => Ok(RequestedRef::Default),
+            (Some(branch), None, None) => Ok(RequestedRef::Branch(branch)),
+            (None, Some(tag), None) => Ok(RequestedRef::Tag(tag)),
+            (None, None, Some(sha)) => {
+                if sha.len() != HASH_LENGTH || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                        "Invalid SHA provided."
+                    )));
+                }
+                Ok(RequestedRef::Sha(sha.to_ascii_lowercase()))
             }
+            _ => Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+                "Only one of branch, tag or sha may be provided."
+            ))),
+        }
+    }
+}
+
+/// A repository whose requested reference has been resolved to a commit on
+/// the remote, ready to be handed to `get_statistics`.
+struct ResolvedRepository {
+    url: String,
+    sha: String,
+    /// The analyzed branch, empty when a tag or a bare SHA was requested.
+    branch: String,
+    tag: Option<String>,
+}
+
+impl ResolvedRepository {
+    /// The reference `get_statistics` fetches: the branch name, `refs/tags/<tag>`
+    /// for tags, or the commit SHA itself. It is also what identifies the
+    /// statistics in cache keys and ETags.
+    fn reference(&self) -> String {
+        match &self.tag {
+            Some(tag) => format!("refs/tags/{}", tag),
+            None if self.branch.is_empty() => self.sha.clone(),
+            None => self.branch.clone(),
+        }
+    }
+}
+
+/// Outcome of `resolve_repository`: either the request was rejected by one of
+/// the configured whitelists or by the full clone queue, or the requested
+/// reference was resolved.
+enum Resolution {
+    Forbidden,
+    Busy,
+    Resolved(ResolvedRepository),
+}
+
+/// Refs advertised by a remote, as listed by `list_remote_refs`.
+struct RemoteRefs {
+    /// Name and object id of every advertised ref.
+    refs: Vec<(String, String)>,
+    /// Target of the remote's `HEAD` symref, when advertised.
+    head: Option<String>,
+}
+
+/// Lists the refs of `url` (like `git ls-remote`) without fetching anything.
+fn list_remote_refs(url: &str) -> Result<RemoteRefs, git2::Error> {
+    let tmp_bare_dir = TempDir::new().map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    let repo = Repository::init_bare(tmp_bare_dir.path())?;
+    let mut remote = repo.remote_anonymous(url)?;
+    remote.connect(Direction::Fetch)?;
+    let refs = remote
+        .list()?
+        .iter()
+        .map(|r| (r.name().to_owned(), r.oid().to_string()))
+        .collect();
+    let head = remote
+        .default_branch()
+        .ok()
+        .and_then(|head| head.as_str().map(str::to_owned));
+    Ok(RemoteRefs { refs, head })
+}
+
+/// Validates `domain`/`user` against the configured whitelists and builds
+/// the URL of the repository, `None` when one of the whitelists rejects it.
+fn repository_url(
+    data: &AppConfig,
+    domain: &str,
+    user: &str,
+    repo: &str,
+) -> actix_web::Result<Option<String>> {
+    // If a whitelist is configured, ensure the requested user is allowed.
+    if let Some(whitelist) = &data.user_whitelist {
+        if !whitelist.contains(user) {
+            log::warn!("User {} not in whitelist, rejecting request", user);
+            return Ok(None);
         }
     }
 
+    let mut domain = percent_encoding::percent_decode_str(domain).decode_utf8()?;
+
+    // For backwards compatibility if a domain isn't specified we append `.com`.
+    if !domain.contains('.') {
+        domain += ".com";
+    }
+
+    // If a gitserver whitelist is configured, ensure the requested domain is allowed.
+    // We normalize the domain to lowercase to compare against the normalized whitelist.
+    let domain_lc = domain.to_ascii_lowercase();
+    if let Some(gsw) = &data.gitserver_whitelist {
+        if !gsw.contains(domain_lc.as_str()) {
+            log::warn!(
+                "Git server {} not in gitserver whitelist, rejecting request",
+                domain
+            );
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(format!("https://{}/{}/{}", domain_lc, user, repo)))
+}
+
+/// Validates `domain`/`user` against the configured whitelists, lists the
+/// remote refs on the worker pool and resolves the requested branch or tag
+/// (or the default head branch) to a commit SHA. Bare SHAs are passed through
+/// without listing refs.
+async fn resolve_repository(
+    data: &AppConfig,
+    domain: &str,
+    user: &str,
+    repo: &str,
+    requested: &RequestedRef,
+) -> actix_web::Result<Resolution> {
+    let Some(url) = repository_url(data, domain, user, repo)? else {
+        return Ok(Resolution::Forbidden);
+    };
+
+    let branch: &str = match requested {
+        RequestedRef::Sha(sha) => {
+            return Ok(Resolution::Resolved(ResolvedRepository {
+                url,
+                sha: sha.clone(),
+                branch: String::new(),
+                tag: None,
+            }))
+        }
+        RequestedRef::Branch(branch) => branch,
+        RequestedRef::Default | RequestedRef::Tag(_) => "",
+    };
+
+    // Use libgit2 via git2 crate to query remote refs and determine branch
+    let remote_url = url.clone();
+    let listed = data
+        .workers
+        .run(move || list_remote_refs(&remote_url))
+        .await;
+    let remote = match pool_outcome(listed, &url, &data.workers)? {
+        Some(listed) => {
+            listed.map_err(|e| actix_web::error::ErrorBadRequest(eyre::eyre!(e.to_string())))?
+        }
+        None => return Ok(Resolution::Busy),
+    };
+
+    if let RequestedRef::Tag(tag) = requested {
+        let sha = resolve_tag(&remote, tag).ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(eyre::eyre!("Invalid tag provided."))
+        })?;
+        return Ok(Resolution::Resolved(ResolvedRepository {
+            url,
+            sha,
+            branch: String::new(),
+            tag: Some(tag.clone()),
+        }));
+    }
+
+    let (branch, sha) = resolve_branch(&remote, &url, branch)?;
+    Ok(Resolution::Resolved(ResolvedRepository {
+        url,
+        sha,
+        branch,
+        tag: None,
+    }))
+}
+
+/// Resolves `tag` to the commit it points to among the listed refs.
+fn resolve_tag(remote: &RemoteRefs, tag: &str) -> Option<String> {
+    // Annotated tags are advertised twice: the tag object itself and the
+    // peeled `^{}` entry pointing at the commit. Prefer the commit.
+    let tag_ref = format!("refs/tags/{}", tag);
+    let peeled_ref = format!("{}^{{}}", tag_ref);
+    remote
+        .refs
+        .iter()
+        .find(|(name, _)| *name == peeled_ref)
+        .or_else(|| remote.refs.iter().find(|(name, _)| *name == tag_ref))
+        .map(|(_, oid)| oid.clone())
+}
+
+/// Resolves `branch`, or the remote's default head branch when empty, to
+/// its name and commit among the refs listed from `url`.
+fn resolve_branch(
+    remote: &RemoteRefs,
+    url: &str,
+    branch: &str,
+) -> actix_web::Result<(String, String)> {
+    let RemoteRefs { refs, head } = remote;
+    // Build a vector of available branch names (refs/heads/*)
+    let available_branches: Vec<String> = refs
+        .iter()
+        .filter_map(|(name, _)| name.strip_prefix("refs/heads/").map(str::to_string))
+        .collect();
+    if available_branches.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Invalid SHA provided."
+        )));
+    }
+
+    // Determine default head branch if not provided by query: use the branch
+    // the remote advertises through its HEAD symref, and only when the server
+    // doesn't advertise one prefer 'main' then 'master' then the first branch
+    let advertised_head: Option<String> = head
+        .as_deref()
+        .and_then(|head| head.strip_prefix("refs/heads/").map(str::to_string))
+        .filter(|head| available_branches.contains(head));
+    let head_branch = if let Some(head) = advertised_head {
+        log::debug!("{} - Remote HEAD points to {}", url, head);
+        head
+    } else if available_branches.contains(&"main".to_string()) {
+        "main".to_string()
+    } else if available_branches.contains(&"master".to_string()) {
+        "master".to_string()
+    } else {
+        available_branches[0].clone()
+    };
+
+    // If the request included a `branch` verify it's available
+    if !branch.is_empty() && !available_branches.iter().any(|b| b == branch) {
+        return Err(actix_web::error::ErrorBadRequest(eyre::eyre!(
+            "Invalid SHA provided."
+        )));
+    }
+
+    let branch_name = if branch.is_empty() {
+        head_branch
+    } else {
+        branch.to_owned()
+    };
+    // Find the oid for the requested branch
+    let mut sha: String = String::new();
+    let target_ref = format!("refs/heads/{}", branch_name);
+    for (name, oid) in refs.iter() {
+        if *name == target_ref {
+            sha = oid.clone();
+            break;
+        }
+    }
+    (sha.len() == HASH_LENGTH)
+        .then_some(())
+        .ok_or_else(|| actix_web::error::ErrorBadRequest(eyre::eyre!("Invalid SHA provided.")))?;
+
+    Ok((branch_name, sha))
+}
+
+/// Returns true when the request carries an `If-None-Match` header matching
+/// the given entity tag.
+fn etag_matches(request: &HttpRequest, etag: &str) -> bool {
+    let Ok(if_none_match) = IfNoneMatch::parse(request) else {
+        return false;
+    };
+    log::debug!("Checking If-None-Match: {}", etag);
+    let entity_tag: EntityTag = EntityTag::new(false, etag.to_owned());
+    match if_none_match {
+        IfNoneMatch::Any => false,
+        IfNoneMatch::Items(items) => items
+            .iter()
+            .any(|etag: &EntityTag| etag.weak_eq(&entity_tag)),
+    }
+}
+
+/// Keeps only the languages listed in the comma-separated `type` query
+/// parameter, silently skipping unknown names. An empty list keeps everything.
+fn filter_language_types(
+    languages: Vec<(LanguageType, Language)>,
+    types: &str,
+) -> Vec<(LanguageType, Language)> {
+    let language_types: HashSet<LanguageType> = types
+        .split(',')
+        .filter_map(|s: &str| str::parse::<LanguageType>(s).ok())
+        .collect::<HashSet<LanguageType>>();
+
+    if language_types.is_empty() {
+        languages
+    } else {
+        languages
+            .into_iter()
+            .filter(|(language_type, _)| language_types.contains(language_type))
+            .collect()
+    }
+}
+
+fn repo_identifier(url: &str, sha: &str, reference: &str) -> String {
+    format!("{}#{}#{}", url, sha, reference)
+}
+
+/// Entity tag of the statistics of a commit: the SHA-256 of its reference and
+/// options, since references, paths, patterns and language names may contain
+/// characters an entity tag can't hold.
+fn etag_identifier(sha: &str, reference: &str, options: &CountOptions, embedded: bool) -> String {
+    let mut identifier = format!("{}#{}{}", sha, reference, options.identifier());
+    if embedded {
+        identifier.push_str("#embedded");
+    }
+    openssl::sha::sha256(identifier.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Key of the statistics of a commit in `CACHE`: the repository identifier,
+/// the ignored file types and the per-request count options.
+fn statistics_key(
+    url: &str,
+    sha: &str,
+    reference: &str,
+    ignore_filetypes: Option<&std::collections::HashSet<String>>,
+    options: &CountOptions,
+) -> String {
+    let mut key = repo_identifier(url, sha, reference);
+    key.push_str(&options_identifier(ignore_filetypes, options));
+    key
+}
+
+/// Suffix of `statistics_key` after the commit: the ignored file types and
+/// the per-request count options. Also identifies the history series.
+fn options_identifier(
+    ignore_filetypes: Option<&std::collections::HashSet<String>>,
+    options: &CountOptions,
+) -> String {
+    let mut identifier = String::new();
+    if let Some(ifts) = ignore_filetypes {
+        let mut v: Vec<String> = ifts.iter().cloned().collect();
+        v.sort();
+        if !v.is_empty() {
+            identifier.push('#');
+            identifier.push_str(&v.join(","));
+        }
+    }
+    identifier.push_str(&options.identifier());
+    identifier
+}
+
+/// Key of `LATEST`: the statistics key without the commit.
+fn latest_key(
+    url: &str,
+    reference: &str,
+    ignore_filetypes: Option<&HashSet<String>>,
+    options: &CountOptions,
+) -> String {
+    statistics_key(url, "", reference, ignore_filetypes, options)
+}
+
+/// Fetches and counts commit `sha` of `url`.
+fn get_statistics(
+    url: &str,
+    sha: &str,
+    reference: &str,
+    ignore_filetypes: Option<&std::collections::HashSet<String>>,
+    options: &CountOptions,
+    mirrors: Option<&mirrors::Mirrors>,
+    progress: &dyn Fn(jobs::Phase),
+) -> eyre::Result<cached::Return<Counts>> {
+    log::info!("{} - Cloning", url);
+    progress(jobs::Phase::Cloning);
+    let counts = with_fetched_commit(
+        url,
+        sha,
+        reference,
+        mirrors,
+        None,
+        |repo, commit, scratch| {
+            log::info!("{} - Getting Statistics", url);
+            progress(jobs::Phase::Counting);
+            count_commit(repo, commit, url, ignore_filetypes, options, scratch)
+        },
+    )?;
+    Ok(cached::Return::new(counts))
+}
+
+/// Fetches commit `sha` of `url` (see `fetch_commit`) into its mirror, or
+/// else into a shallow temporary repository, and runs `f` on it along with an
+/// empty scratch directory.
+///
+/// Without a mirror, a `repository` directory can be given instead of the
+/// temporary repository: the commit is fetched into it with its whole history
+/// unless it is already there.
+fn with_fetched_commit<T>(
+    url: &str,
+    sha: &str,
+    reference: &str,
+    mirrors: Option<&mirrors::Mirrors>,
+    repository: Option<&Path>,
+    f: impl FnOnce(&Repository, &git2::Commit<'_>, &Path) -> eyre::Result<T>,
+) -> eyre::Result<T> {
+    let temp_dir: TempDir = TempDir::new()?;
+    let scratch = temp_dir.path().join("scratch");
+    std::fs::create_dir(&scratch)?;
+
+    // Fetch the reference using libgit2 with optional credentials
+    let mut fo = FetchOptions::new();
+    let mut callbacks = RemoteCallbacks::new();
+    // Use GITHUB_TOKEN if available for HTTPS auth (x-access-token)
+    if let Ok(token) = std::env::var("GITHUB_TOKEN") {
+        callbacks.credentials(move |_, _username_from_url, _| {
+            // Use username "x-access-token" as suggested by GitHub for personal access tokens
+            Cred::userpass_plaintext("x-access-token", &token)
+        });
+    }
+    fo.remote_callbacks(callbacks);
+
+    match mirrors {
+        Some(mirrors) => mirrors.with_commit(url, sha, reference, &mut fo, |repo, commit| {
+            f(repo, commit, &scratch)
+        }),
+        None => {
+            let repo = match repository {
+                Some(dir) => Repository::open_bare(dir).or_else(|_| Repository::init_bare(dir))?,
+                None => {
+                    fo.depth(1);
+                    Repository::init_bare(temp_dir.path().join("repository.git"))?
+                }
+            };
+            let fetched = match git2::Oid::from_str(sha).and_then(|oid| repo.find_commit(oid)) {
+                Ok(commit) => Ok(commit),
+                Err(_) => fetch_commit(&repo, url, sha, reference, &mut fo),
+            };
+            let commit = fetched.map_err(|e| eyre::eyre!(e.to_string()))?;
+            f(&repo, &commit, &scratch)
+        }
+    }
+}
+
+/// Counts the files of `commit` straight from `repo`, see `tree_count`.
+/// `scratch` is an empty directory the counting may write to.
+fn count_commit(
+    repo: &Repository,
+    commit: &git2::Commit<'_>,
+    url: &str,
+    ignore_filetypes: Option<&std::collections::HashSet<String>>,
+    options: &CountOptions,
+    scratch: &Path,
+) -> eyre::Result<Counts> {
+    let tree = commit.tree()?;
+    // The repository's own `.tokeisrv.toml` adds to the request's excludes;
+    // a broken one is ignored rather than failing the badge.
+    let repo_config = repo_config::RepoConfig::load(repo, &tree).unwrap_or_else(|e| {
+        log::warn!("{} - Ignoring {}: {}", url, repo_config::FILE_NAME, e);
+        repo_config::RepoConfig::default()
+    });
+    let mut exclude: Vec<String> = options.exclude.clone();
+    exclude.extend(repo_config.exclude.iter().cloned());
+    // Build exclude patterns from configured ignore filetypes.
+    // Convert extension `foo` to glob pattern `**/*.foo`.
+    if let Some(ifts) = ignore_filetypes {
+        for ext in ifts {
+            // ignore trailing dots or accidental leading dots
+            let normalized_ext = ext.trim().trim_start_matches('.');
+            if !normalized_ext.is_empty() {
+                exclude.push(format!("**/*.{}", normalized_ext));
+            }
+        }
+    }
+    let glob_filter = filters::GlobFilter::new(&options.include, &exclude)?;
+    let overrides = repo_config.language_overrides();
+    let config = options.tokei.to_config();
+    let languages =
+        tree_count::TreeCounter::new(repo, &config, &glob_filter, &overrides, &BLOBS, scratch)
+            .count(&tree, options.path.as_deref())?;
+
     let mut languages_sorted_by_lines_of_code: Vec<(LanguageType, Language)> =
         languages.into_iter().collect();
-    languages_sorted_by_lines_of_code.sort_by(|(_, a), (_, b)| b.code.cmp(&a.code));
+    languages_sorted_by_lines_of_code.sort_by_key(|(_, language)| std::cmp::Reverse(language.code));
 
-    Ok(cached::Return::new(languages_sorted_by_lines_of_code))
+    Ok(Counts {
+        languages: languages_sorted_by_lines_of_code,
+        default_category: repo_config.category,
+        committed_at: Some(commit.time().seconds()),
+    })
+}
+
+/// Fetches `reference` (a branch name, a `refs/tags/*` ref or a commit SHA)
+/// from `url` into `repo` and returns commit `sha`.
+///
+/// Branches and tags are fetched by name since every server allows it; bare
+/// SHAs rely on the server accepting unadvertised object ids. If a branch moved
+/// between listing the refs and fetching, `sha` may not have been fetched: an
+/// error is returned rather than counting another commit under its SHA.
+fn fetch_commit<'r>(
+    repo: &'r Repository,
+    url: &str,
+    sha: &str,
+    reference: &str,
+    fo: &mut FetchOptions<'_>,
+) -> Result<git2::Commit<'r>, git2::Error> {
+    let refspec = if reference == sha || reference.starts_with("refs/") {
+        reference.to_owned()
+    } else {
+        format!("refs/heads/{}", reference)
+    };
+    repo.remote_anonymous(url)?
+        .fetch(&[refspec.as_str()], Some(fo), None)?;
+
+    match git2::Oid::from_str(sha).and_then(|oid| repo.find_object(oid, None)) {
+        Ok(object) => object.peel_to_commit(),
+        Err(_) => Err(git2::Error::from_str(&format!(
+            "{} moved while it was fetched, {} is no longer its head",
+            reference, sha
+        ))),
+    }
 }
 
 fn trim_and_float(num: usize, trim: usize) -> f64 {
@@ -731,24 +2452,90 @@ async fn make_badge_style(
     }
 }
 
+/// JSON body of the badge endpoint: the summed tokei `Language` plus the
+/// commit and branch (or tag) it was computed from.
+#[derive(serde::Serialize)]
+struct BadgeJson<'a> {
+    #[serde(flatten)]
+    stats: &'a Language,
+    sha: &'a str,
+    branch: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stale: bool,
+}
+
+/// Body of the shields.io endpoint badge format, see
+/// <https://shields.io/badges/endpoint-badge>.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShieldsEndpoint<'a> {
+    schema_version: u8,
+    label: &'a str,
+    message: &'a str,
+    /// Hex color without the leading `#`, or a named color.
+    color: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    named_logo: &'a str,
+    style: &'a str,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    is_error: bool,
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn make_badge(
-    content_type: &ContentType,
+    format: BadgeFormat,
+    resolved: &ResolvedRepository,
+    stale: bool,
     stats: &Language,
     category: &str,
     label: &str,
     style: &str,
     color: &str,
     logo: &str,
+    named_logo: &str,
     ranking_language: &str,
     no_label: bool,
 ) -> actix_web::Result<String> {
-    if *content_type == ContentType::json() {
-        return Ok(serde_json::to_string(&stats)?);
+    if format == BadgeFormat::Json {
+        return Ok(serde_json::to_string(&BadgeJson {
+            stats,
+            sha: &resolved.sha,
+            branch: &resolved.branch,
+            tag: resolved.tag.as_deref(),
+            stale,
+        })?);
+    }
+
+    let (label, message) = badge_text(stats, category, label, ranking_language, no_label);
+
+    if format == BadgeFormat::Shields {
+        return Ok(serde_json::to_string(&ShieldsEndpoint {
+            schema_version: 1,
+            label,
+            message: &message,
+            color: color.trim_start_matches('#'),
+            named_logo,
+            style,
+            is_error: false,
+        })?);
     }
 
+    make_badge_style(label, &message, color, style, logo).await
+}
+
+/// Left and right-hand side texts of a badge: the label and either the
+/// ranked language or the formatted amount of `category`.
+fn badge_text<'a>(
+    stats: &Language,
+    category: &str,
+    label: &'a str,
+    ranking_language: &str,
+    no_label: bool,
+) -> (&'a str, String) {
     if !ranking_language.is_empty() {
-        return make_badge_style(label, ranking_language, color, style, logo).await;
+        return (label, ranking_language.to_owned());
     }
 
     let (amount, label) = match category {
@@ -758,8 +2545,12 @@ async fn make_badge(
         "comments" => (stats.comments, if no_label { COMMENTS } else { label }),
         _ => (stats.lines(), if no_label { LINES } else { label }),
     };
+    (label, format_amount(amount))
+}
 
-    let amount: String = if amount >= BILLION {
+/// Formats a count the way badges display it, e.g. `1.2K` or `3.4M`.
+fn format_amount(amount: usize) -> String {
+    if amount >= BILLION {
         format!("{:.1}B", trim_and_float(amount, BILLION))
     } else if amount >= MILLION {
         format!("{:.1}M", trim_and_float(amount, MILLION))
@@ -767,7 +2558,355 @@ async fn make_badge(
         format!("{:.1}K", trim_and_float(amount, THOUSAND))
     } else {
         amount.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        attribute_embedded, embedded_statistics, etag_identifier, etag_matches, make_badge,
+        normalize_subdirectory, options_identifier, resolve_branch, resolve_tag, BadgeFormat,
+        CountOptions, RemoteRefs, RequestedRef, ResolvedRepository, TokeiOptions, CONTENT_TYPE_SVG,
+    };
+    use actix_web::{
+        http::{
+            header::{
+                CacheControl, CacheDirective, ContentType, EntityTag, CACHE_CONTROL, CONTENT_TYPE,
+                ETAG, IF_NONE_MATCH,
+            },
+            StatusCode,
+        },
+        test::TestRequest,
+        HttpResponse,
+    };
+    use std::{path::PathBuf, sync::Arc};
+    use tokei::{Language, LanguageType, Report};
+
+    use crate::{
+        cache::MemoryCache, jobs::Jobs, pool::WorkerPool, single_flight::SingleFlight, AppConfig,
     };
 
-    make_badge_style(label, &amount, color, style, logo).await
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    /// Configuration of a server with every optional feature disabled.
+    pub fn app_config() -> AppConfig {
+        AppConfig {
+            user_whitelist: None,
+            gitserver_whitelist: None,
+            ignore_filetypes: None,
+            tokei_defaults: TokeiOptions::default(),
+            workers: Arc::new(WorkerPool::new(1, 1)),
+            in_flight: Arc::new(SingleFlight::new()),
+            cache: Arc::new(MemoryCache::default()),
+            disk_cache: None,
+            admin_token: None,
+            webhook_secret: None,
+            jobs: Arc::new(Jobs::default()),
+            mirrors: None,
+            history: None,
+        }
+    }
+
+    /// Asserts that the statistics of `options` answer 200 with an ETag a
+    /// later `If-None-Match` matches.
+    fn assert_etag_is_valid(options: &CountOptions) {
+        let etag = etag_identifier(SHA, "main", options, false);
+        let response = respond!(Ok, ContentType::json(), "{}", etag.clone());
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = TestRequest::default()
+            .insert_header((IF_NONE_MATCH, format!("\"{}\"", etag)))
+            .to_http_request();
+        assert!(etag_matches(&request, &etag));
+    }
+
+    #[test]
+    fn multi_word_types_make_valid_etags() {
+        let options = CountOptions {
+            tokei: TokeiOptions {
+                types: vec![LanguageType::CHeader, LanguageType::VisualBasic],
+                ..TokeiOptions::default()
+            },
+            ..CountOptions::default()
+        };
+        assert!(options.identifier().contains("C Header"));
+        assert_etag_is_valid(&options);
+    }
+
+    #[test]
+    fn patterns_with_spaces_and_quotes_make_valid_etags() {
+        let options = CountOptions {
+            include: crate::filters::parse_patterns(Some(r#"my dir/**"#)).unwrap(),
+            exclude: crate::filters::parse_patterns(Some(r#""quoted".rs"#)).unwrap(),
+            ..CountOptions::default()
+        };
+        assert_etag_is_valid(&options);
+    }
+
+    fn oid(digit: char) -> String {
+        digit.to_string().repeat(40)
+    }
+
+    #[test]
+    fn annotated_tags_resolve_to_their_commit() {
+        let remote = RemoteRefs {
+            refs: vec![
+                ("refs/tags/v1^{}".to_owned(), oid('2')),
+                ("refs/tags/v1".to_owned(), oid('1')),
+                ("refs/tags/v2".to_owned(), oid('3')),
+                ("refs/tags/v2.1".to_owned(), oid('4')),
+                ("refs/tags/v2.1^{}".to_owned(), oid('5')),
+            ],
+            head: None,
+        };
+        // Annotated, whichever order the entries are advertised in.
+        assert_eq!(resolve_tag(&remote, "v1"), Some(oid('2')));
+        assert_eq!(resolve_tag(&remote, "v2.1"), Some(oid('5')));
+        // Lightweight.
+        assert_eq!(resolve_tag(&remote, "v2"), Some(oid('3')));
+        assert_eq!(resolve_tag(&remote, "v3"), None);
+    }
+
+    #[test]
+    fn only_one_reference_may_be_requested() {
+        let requested = |branch: &str, tag: &str, sha: &str| {
+            let given = |v: &str| Some(v.to_owned());
+            RequestedRef::from_query(given(branch), given(tag), given(sha))
+        };
+        assert!(matches!(requested("", "", ""), Ok(RequestedRef::Default)));
+        assert!(matches!(
+            RequestedRef::from_query(None, None, None),
+            Ok(RequestedRef::Default)
+        ));
+        assert!(matches!(requested("dev", "", ""), Ok(RequestedRef::Branch(b)) if b == "dev"));
+        assert!(matches!(requested("", "v1", ""), Ok(RequestedRef::Tag(t)) if t == "v1"));
+        assert!(
+            matches!(requested("", "", &oid('A')), Ok(RequestedRef::Sha(sha)) if sha == oid('a'))
+        );
+
+        assert!(requested("", "", "abc123").is_err());
+        assert!(requested("", "", &"g".repeat(40)).is_err());
+        assert!(requested("dev", "v1", "").is_err());
+        assert!(requested("dev", "", &oid('a')).is_err());
+        assert!(requested("", "v1", &oid('a')).is_err());
+        assert!(requested("dev", "v1", &oid('a')).is_err());
+    }
+
+    #[test]
+    fn default_branch_falls_back_from_head_to_main_then_master() {
+        let remote = |branches: &[&str], head: Option<&str>| RemoteRefs {
+            refs: branches
+                .iter()
+                .enumerate()
+                .map(|(i, branch)| {
+                    let digit = char::from_digit(i as u32 + 1, 16).unwrap();
+                    (format!("refs/heads/{}", branch), oid(digit))
+                })
+                .collect(),
+            head: head.map(str::to_owned),
+        };
+        let default = |remote: &RemoteRefs| resolve_branch(remote, "url", "").unwrap();
+
+        let branches = ["develop", "master", "main"];
+        assert_eq!(
+            default(&remote(&branches, Some("refs/heads/develop"))),
+            ("develop".to_owned(), oid('1'))
+        );
+        // A HEAD pointing outside the advertised branches is ignored.
+        assert_eq!(
+            default(&remote(&branches, Some("refs/heads/gone"))),
+            ("main".to_owned(), oid('3'))
+        );
+        assert_eq!(
+            default(&remote(&branches, None)),
+            ("main".to_owned(), oid('3'))
+        );
+        assert_eq!(
+            default(&remote(&["develop", "master"], None)),
+            ("master".to_owned(), oid('2'))
+        );
+        assert_eq!(
+            default(&remote(&["develop", "trunk"], None)),
+            ("develop".to_owned(), oid('1'))
+        );
+
+        // A requested branch wins over the default one, but must exist.
+        let remote = remote(&branches, Some("refs/heads/develop"));
+        assert_eq!(
+            resolve_branch(&remote, "url", "master").unwrap(),
+            ("master".to_owned(), oid('2'))
+        );
+        assert!(resolve_branch(&remote, "url", "gone").is_err());
+        assert!(resolve_branch(
+            &RemoteRefs {
+                refs: Vec::new(),
+                head: None
+            },
+            "url",
+            ""
+        )
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn shields_logo_is_a_named_logo_only() {
+        let resolved = ResolvedRepository {
+            url: "https://github.com/owner/repo".to_owned(),
+            sha: SHA.to_owned(),
+            branch: "main".to_owned(),
+            tag: None,
+        };
+        let shields = |logo: &'static str, named_logo: &'static str| {
+            let resolved = &resolved;
+            async move {
+                let body = make_badge(
+                    BadgeFormat::Shields,
+                    resolved,
+                    false,
+                    &Language::new(),
+                    "code",
+                    "loc",
+                    "flat",
+                    "#007ec6",
+                    logo,
+                    named_logo,
+                    "",
+                    false,
+                )
+                .await
+                .unwrap();
+                serde_json::from_str::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let badge = shields("data:image/svg+xml;base64,PHN2Zz4=", "").await;
+        assert!(badge.get("namedLogo").is_none());
+        assert_eq!(badge["color"], "007ec6");
+        let badge = shields("https://example.com/logo.svg", "rust").await;
+        assert_eq!(badge["namedLogo"], "rust");
+    }
+
+    const MARKDOWN: &str = "# Title
+
+Some prose.
+
+```rust
+// Entry point.
+fn main() {
+    println!(\"Hello\");
+}
+```
+";
+
+    const HTML: &str = "<html>
+<head>
+<style>
+body { color: red; }
+</style>
+</head>
+<body></body>
+</html>
+";
+
+    /// A language made of one file of each given content.
+    fn language(language_type: LanguageType, files: &[&str]) -> Language {
+        let mut language = Language::new();
+        for (i, text) in files.iter().enumerate() {
+            let mut report = Report::new(PathBuf::from(i.to_string()));
+            report.stats = language_type.parse_from_str(text, &tokei::Config::default());
+            language.add_report(report);
+        }
+        language.total();
+        language
+    }
+
+    #[test]
+    fn embedded_code_is_credited_to_its_language_once() {
+        let languages = vec![
+            (
+                LanguageType::Rust,
+                language(LanguageType::Rust, &["fn f() {}\n"]),
+            ),
+            (
+                LanguageType::Markdown,
+                language(LanguageType::Markdown, &[MARKDOWN]),
+            ),
+            (LanguageType::Html, language(LanguageType::Html, &[HTML])),
+        ];
+        let embedded = embedded_statistics(&languages);
+        assert_eq!(embedded[&LanguageType::Rust].code, 3);
+        assert_eq!(embedded[&LanguageType::Rust].comments, 1);
+        assert_eq!(embedded[&LanguageType::Css].code, 1);
+
+        let attributed = attribute_embedded(languages.clone(), &embedded);
+        let find = |language_type: LanguageType| {
+            attributed
+                .iter()
+                .find(|(found, _)| *found == language_type)
+                .map(|(_, language)| language)
+                .unwrap()
+        };
+        let rust = find(LanguageType::Rust);
+        assert_eq!((rust.code, rust.comments, rust.reports.len()), (4, 1, 1));
+        let css = find(LanguageType::Css);
+        assert_eq!((css.code, css.reports.len()), (1, 0));
+        // The parents keep their own lines and files, without the children.
+        for (language_type, language) in &languages {
+            let parent = find(*language_type);
+            if *language_type != LanguageType::Rust {
+                assert_eq!(parent.lines(), language.lines());
+            }
+            assert_eq!(parent.reports.len(), language.reports.len());
+            assert!(parent.children.is_empty());
+        }
+        // Every line is counted exactly once.
+        let summarised: usize = languages.iter().map(|(_, l)| l.summarise().lines()).sum();
+        let attributed_lines: usize = attributed.iter().map(|(_, l)| l.lines()).sum();
+        assert_eq!(attributed_lines, summarised);
+    }
+
+    #[test]
+    fn ignored_file_types_identify_the_options() {
+        let options = CountOptions {
+            path: Some("src".to_owned()),
+            ..CountOptions::default()
+        };
+        let ignored: std::collections::HashSet<String> =
+            ["png", "gz"].iter().map(|t| t.to_string()).collect();
+        assert_eq!(options_identifier(None, &options), "#path=src");
+        assert_eq!(
+            options_identifier(Some(&ignored), &options),
+            "#gz,png#path=src"
+        );
+        assert_eq!(
+            options_identifier(Some(&Default::default()), &options),
+            "#path=src"
+        );
+    }
+
+    #[test]
+    fn subdirectories_are_normalized() {
+        let normalize = |path: &str| normalize_subdirectory(Some(path)).unwrap();
+        assert_eq!(normalize_subdirectory(None).unwrap(), None);
+        assert_eq!(normalize("/src//parser/./"), Some("src/parser".to_owned()));
+        assert_eq!(normalize("./"), None);
+        assert_eq!(normalize(""), None);
+    }
+
+    #[test]
+    fn subdirectories_with_spaces_and_quotes_make_valid_etags() {
+        for path in ["my dir", r#"docs/"quoted" dir"#] {
+            let options = CountOptions {
+                path: normalize_subdirectory(Some(path)).unwrap(),
+                ..CountOptions::default()
+            };
+            assert_eq!(options.path.as_deref(), Some(path));
+            assert_etag_is_valid(&options);
+        }
+    }
+
+    #[test]
+    fn traversal_is_rejected() {
+        for path in ["..", "src/../..", "/..", "src/..", "src\\..\\..", "src\0"] {
+            assert!(normalize_subdirectory(Some(path)).is_err(), "{:?}", path);
+        }
+    }
 }
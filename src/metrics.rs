@@ -0,0 +1,102 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Operational metrics in the Prometheus text exposition format.
+
+use actix_web::{get, http::header::ContentType, web, HttpResponse};
+
+use crate::{AppConfig, BLOBS};
+
+/// Counters and gauges of the worker pool, of request coalescing and of the
+/// statistics and blob caches.
+#[get("/metrics")]
+pub async fn metrics(data: web::Data<AppConfig>) -> HttpResponse {
+    let mut body = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        body.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+        ));
+    };
+    metric(
+        "tokeisrv_counts_total",
+        "counter",
+        "Repository analyses run (cache misses not coalesced with another request).",
+        data.in_flight.leaders().to_string(),
+    );
+    metric(
+        "tokeisrv_coalesced_total",
+        "counter",
+        "Requests that shared the result of an in-flight analysis.",
+        data.in_flight.coalesced().to_string(),
+    );
+    metric(
+        "tokeisrv_in_flight",
+        "gauge",
+        "Analyses currently running or waiting for a clone slot.",
+        data.in_flight.in_flight().to_string(),
+    );
+    metric(
+        "tokeisrv_clone_queue",
+        "gauge",
+        "Jobs waiting for a clone slot.",
+        data.workers.queued().to_string(),
+    );
+    let stats = data.cache.stats();
+    metric(
+        "tokeisrv_cache_hits_total",
+        "counter",
+        "Requests served from the statistics cache.",
+        stats.hits().to_string(),
+    );
+    metric(
+        "tokeisrv_cache_misses_total",
+        "counter",
+        "Requests whose statistics weren't cached.",
+        stats.misses().to_string(),
+    );
+    if data.cache.tracks_evictions() {
+        metric(
+            "tokeisrv_cache_evictions_total",
+            "counter",
+            "Cache entries dropped because they expired or to make room for new ones.",
+            stats.evictions().to_string(),
+        );
+    }
+    if BLOBS.is_enabled() {
+        metric(
+            "tokeisrv_blob_cache_hits_total",
+            "counter",
+            "Files whose count was reused from the blob cache.",
+            BLOBS.hits().to_string(),
+        );
+        metric(
+            "tokeisrv_blob_cache_misses_total",
+            "counter",
+            "Files parsed because their blob wasn't in the blob cache.",
+            BLOBS.misses().to_string(),
+        );
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(body)
+}
@@ -0,0 +1,341 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Bare mirrors of the counted repositories, kept under `--mirror-dir` so
+//! that a repository counted again is fetched incrementally instead of being
+//! cloned from scratch. Commits are counted straight out of the mirror.
+//!
+//! Mirrors are named after a hash of their URL. Once their total size
+//! exceeds `--mirror-max-size`, the least recently used ones are removed;
+//! after a restart, a mirror was last used when it was last fetched.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use git2::{FetchOptions, Oid, Repository};
+
+struct Mirror {
+    /// Disk usage in bytes, as of the last fetch.
+    size: u64,
+    last_used: SystemTime,
+    /// Number of threads fetching into or counting out of the mirror, which
+    /// isn't evicted meanwhile.
+    users: usize,
+    /// Held while the mirror is fetched into or counted out of.
+    lock: Arc<Mutex<()>>,
+}
+
+pub struct Mirrors {
+    root: PathBuf,
+    max_size: u64,
+    /// Mirrors by directory name.
+    mirrors: Mutex<HashMap<String, Mirror>>,
+}
+
+impl Mirrors {
+    /// Opens the mirror directory, creating it if needed, measures the
+    /// mirrors already in it and evicts them down to `max_size`.
+    pub fn open(root: &Path, max_size: u64) -> eyre::Result<Mirrors> {
+        fs::create_dir_all(root)?;
+        let mut mirrors = HashMap::new();
+        for entry in fs::read_dir(root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".git") || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            mirrors.insert(
+                name,
+                Mirror {
+                    size: dir_size(&entry.path()),
+                    last_used: entry.metadata()?.modified()?,
+                    users: 0,
+                    lock: Arc::default(),
+                },
+            );
+        }
+        let mirrors = Mirrors {
+            root: root.to_owned(),
+            max_size,
+            mirrors: Mutex::new(mirrors),
+        };
+        mirrors.evict(None);
+        Ok(mirrors)
+    }
+
+    /// Number of mirrors and their total size in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        let mirrors = self.mirrors.lock().unwrap();
+        (
+            mirrors.len(),
+            mirrors.values().map(|mirror| mirror.size).sum(),
+        )
+    }
+
+    /// Runs `f` on commit `sha` of the mirror of `url`, which isn't evicted
+    /// meanwhile. `reference` (a branch name, a `refs/tags/*` ref or the commit
+    /// SHA itself) is fetched into the mirror first unless the commit is
+    /// already there. If a branch moved since it was listed, `sha` may not
+    /// have been fetched: an error is returned rather than running `f` on
+    /// another commit.
+    pub fn with_commit<T>(
+        &self,
+        url: &str,
+        sha: &str,
+        reference: &str,
+        fo: &mut FetchOptions<'_>,
+        f: impl FnOnce(&Repository, &git2::Commit<'_>) -> eyre::Result<T>,
+    ) -> eyre::Result<T> {
+        let name = mirror_name(url);
+        let in_use = self.use_mirror(&name);
+        let guard = in_use.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let dir = self.root.join(&name);
+        let repo = match Repository::open_bare(&dir) {
+            Ok(repo) => repo,
+            Err(_) => Repository::init_bare(&dir)?,
+        };
+        let mut fetched = false;
+        let commit = match find_commit(&repo, sha) {
+            Some(commit) => {
+                log::info!("{} - Commit found in the mirror", url);
+                commit
+            }
+            None => {
+                log::info!("{} - Fetching into the mirror", url);
+                let target = if reference == sha {
+                    None
+                } else if reference.starts_with("refs/") {
+                    Some(reference.to_owned())
+                } else {
+                    Some(format!("refs/heads/{}", reference))
+                };
+                let refspec = match &target {
+                    Some(target) => format!("+{}:{}", target, target),
+                    None => sha.to_owned(),
+                };
+                repo.remote_anonymous(url)?
+                    .fetch(&[refspec.as_str()], Some(fo), None)?;
+                fetched = true;
+                find_commit(&repo, sha).ok_or_else(|| {
+                    eyre::eyre!(
+                        "{} moved while it was fetched, {} is no longer its head",
+                        reference,
+                        sha
+                    )
+                })?
+            }
+        };
+        let result = f(&repo, &commit);
+        drop(commit);
+        drop(repo);
+        drop(guard);
+        drop(in_use);
+
+        if fetched {
+            let size = dir_size(&dir);
+            if let Some(mirror) = self.mirrors.lock().unwrap().get_mut(&name) {
+                mirror.size = size;
+            }
+            self.evict(Some(&name));
+        }
+        result
+    }
+
+    /// Marks a mirror as used, registering it if needed. It can't be evicted
+    /// until the returned guard is dropped.
+    fn use_mirror(&self, name: &str) -> InUse<'_> {
+        let mut mirrors = self.mirrors.lock().unwrap();
+        let mirror = mirrors.entry(name.to_owned()).or_insert_with(|| Mirror {
+            size: 0,
+            last_used: SystemTime::now(),
+            users: 0,
+            lock: Arc::default(),
+        });
+        mirror.last_used = SystemTime::now();
+        mirror.users += 1;
+        InUse {
+            mirrors: self,
+            name: name.to_owned(),
+            lock: mirror.lock.clone(),
+        }
+    }
+
+    /// Removes the least recently used mirrors other than `keep` until the
+    /// total size fits `max_size`. Mirrors in use are skipped.
+    fn evict(&self, keep: Option<&str>) {
+        let mut mirrors = self.mirrors.lock().unwrap();
+        let mut total: u64 = mirrors.values().map(|mirror| mirror.size).sum();
+        let mut candidates: Vec<(SystemTime, String)> = mirrors
+            .iter()
+            .filter(|(name, _)| Some(name.as_str()) != keep)
+            .map(|(name, mirror)| (mirror.last_used, name.clone()))
+            .collect();
+        candidates.sort();
+        for (_, name) in candidates {
+            if total <= self.max_size {
+                break;
+            }
+            let size = match mirrors.get(&name) {
+                Some(mirror) if mirror.users == 0 => mirror.size,
+                _ => continue,
+            };
+            match fs::remove_dir_all(self.root.join(&name)) {
+                Ok(()) => {
+                    log::info!("Evicted mirror {} ({} bytes)", name, size);
+                    mirrors.remove(&name);
+                    total = total.saturating_sub(size);
+                }
+                Err(e) => log::warn!("Unable to remove mirror {}: {}", name, e),
+            }
+        }
+    }
+}
+
+/// A mirror marked as used by `Mirrors::use_mirror`.
+struct InUse<'a> {
+    mirrors: &'a Mirrors,
+    name: String,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Drop for InUse<'_> {
+    fn drop(&mut self) {
+        let mut mirrors = self
+            .mirrors
+            .mirrors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(mirror) = mirrors.get_mut(&self.name) {
+            mirror.users -= 1;
+        }
+    }
+}
+
+/// Directory name of the mirror of `url`.
+fn mirror_name(url: &str) -> String {
+    let hash: String = openssl::sha::sha256(url.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}.git", hash)
+}
+
+/// The commit `sha` points to (peeling annotated tags), if it is mirrored.
+fn find_commit<'r>(repo: &'r Repository, sha: &str) -> Option<git2::Commit<'r>> {
+    let oid = Oid::from_str(sha).ok()?;
+    repo.find_object(oid, None).ok()?.peel_to_commit().ok()
+}
+
+/// Total size of the files under `path`, in bytes.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use tempfile::TempDir;
+
+    use super::Mirrors;
+
+    /// Creates a mirror directory holding `size` bytes, last used `age` ago.
+    fn mirror(root: &Path, name: &str, size: usize, age: u64) {
+        let dir = root.join(name);
+        fs::create_dir_all(dir.join("objects")).unwrap();
+        fs::write(dir.join("objects").join("pack"), vec![0u8; size]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age);
+        fs::File::open(&dir)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    fn names(root: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn least_recently_used_mirrors_are_evicted_on_open() {
+        let root = TempDir::new().unwrap();
+        mirror(root.path(), "old.git", 100, 300);
+        mirror(root.path(), "older.git", 100, 400);
+        mirror(root.path(), "new.git", 100, 100);
+        mirror(root.path(), "newest.git", 100, 0);
+        // Not a mirror: neither measured nor evicted.
+        mirror(root.path(), "scratch", 1000, 1000);
+
+        let mirrors = Mirrors::open(root.path(), 250).unwrap();
+        assert_eq!(mirrors.usage(), (2, 200));
+        assert_eq!(names(root.path()), ["new.git", "newest.git", "scratch"]);
+    }
+
+    #[test]
+    fn mirrors_in_use_are_kept() {
+        let root = TempDir::new().unwrap();
+        mirror(root.path(), "a.git", 100, 300);
+        mirror(root.path(), "b.git", 100, 200);
+        mirror(root.path(), "c.git", 100, 100);
+        let mirrors = Mirrors::open(root.path(), 300).unwrap();
+        assert_eq!(mirrors.usage(), (3, 300));
+
+        // `a` is the least recently used mirror, but in use: `b` goes when a
+        // fetch grows `c` instead.
+        let in_use = mirrors.use_mirror("a.git");
+        {
+            let mut map = mirrors.mirrors.lock().unwrap();
+            map.get_mut("a.git").unwrap().last_used = SystemTime::UNIX_EPOCH;
+            map.get_mut("c.git").unwrap().size = 200;
+        }
+        mirrors.evict(Some("c.git"));
+        assert_eq!(names(root.path()), ["a.git", "c.git"]);
+        assert_eq!(mirrors.usage(), (2, 300));
+
+        drop(in_use);
+        assert_eq!(mirrors.mirrors.lock().unwrap()["a.git"].users, 0);
+    }
+}
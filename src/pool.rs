@@ -0,0 +1,119 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Bounded pool running the blocking git and tokei work (listing remote refs,
+//! cloning and counting) away from the actix workers, so that a slow clone
+//! only delays the requests queued behind it.
+//!
+//! At most `max_concurrent` jobs run at once on actix's blocking thread pool.
+//! Further jobs wait in first-come, first-served order, and once `queue_size`
+//! of them are waiting new ones are rejected right away.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Semaphore;
+
+/// Why a job couldn't run to completion.
+#[derive(Debug)]
+pub enum PoolError {
+    /// Too many jobs are already waiting.
+    QueueFull,
+    /// The job panicked.
+    Failed,
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PoolError::QueueFull => write!(f, "too many repositories are being analyzed"),
+            PoolError::Failed => write!(f, "the analysis failed unexpectedly"),
+        }
+    }
+}
+
+pub struct WorkerPool {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_size: usize,
+}
+
+/// Removes a waiting job from the queue count, even when the request is
+/// dropped while waiting.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WorkerPool {
+    pub fn new(max_concurrent: usize, queue_size: usize) -> Self {
+        WorkerPool {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            queued: AtomicUsize::new(0),
+            queue_size,
+        }
+    }
+
+    /// Number of jobs currently waiting for a free slot.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Runs `job` on the blocking thread pool once a slot is free. The slot is
+    /// held until `job` returns, even if the caller stops waiting for it.
+    pub async fn run<F, T>(&self, job: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::SeqCst) >= self.queue_size {
+                    self.queued.fetch_sub(1, Ordering::SeqCst);
+                    return Err(PoolError::QueueFull);
+                }
+                let _queued = Queued(&self.queued);
+                // The semaphore is never closed, and it hands out permits in
+                // the order they were requested.
+                self.permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| PoolError::Failed)?
+            }
+        };
+
+        actix_web::web::block(move || {
+            let result = job();
+            drop(permit);
+            result
+        })
+        .await
+        .map_err(|_| PoolError::Failed)
+    }
+}
@@ -0,0 +1,224 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Repository-level configuration read from a `.tokeisrv.toml` file committed
+//! at the root of the measured repository, so that its owners can keep their
+//! exclusion policy next to their code instead of in every badge URL:
+//!
+//! ```toml
+//! # `.gitignore`-style patterns of files left out of the count.
+//! exclude = ["vendor", "**/*.generated.rs"]
+//! # Category shown by badges and charts that don't ask for one.
+//! category = "code"
+//!
+//! # Language forced on the files matching a pattern (the longest matching
+//! # pattern wins), using tokei's language names.
+//! [languages]
+//! "*.inc" = "PHP"
+//! "include/**/*.h" = "C++"
+//! ```
+//!
+//! `.tokeignore` files are honoured as well unless `no_ignore` is set, see
+//! `tree_count`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use globset::GlobSet;
+use tokei::LanguageType;
+
+use crate::filters;
+
+/// Name of the configuration file, looked up at the repository root.
+pub const FILE_NAME: &str = ".tokeisrv.toml";
+/// Configuration files larger than this are rejected.
+const MAX_FILE_SIZE: u64 = 64 * 1024;
+/// Badge categories a configuration file may pick as default.
+const CATEGORIES: [&str; 5] = ["lines", "code", "comments", "blanks", "files"];
+
+/// Contents of `.tokeisrv.toml`, see the module documentation.
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    pub exclude: Vec<String>,
+    pub category: Option<String>,
+    pub languages: BTreeMap<String, String>,
+}
+
+impl RepoConfig {
+    /// Reads the configuration file at the root of a commit's tree, the
+    /// default configuration when there is none. Invalid patterns, unknown
+    /// languages and categories are dropped so that a typo only loses that
+    /// setting.
+    pub fn load(repo: &git2::Repository, tree: &git2::Tree<'_>) -> eyre::Result<RepoConfig> {
+        let Some(entry) = tree.get_name(FILE_NAME) else {
+            return Ok(RepoConfig::default());
+        };
+        // Symbolic links are blobs holding the target path: reject them.
+        let blob = match entry.kind() {
+            Some(git2::ObjectType::Blob) if entry.filemode() != i32::from(git2::FileMode::Link) => {
+                repo.find_blob(entry.id())?
+            }
+            _ => return Err(not_regular_file()),
+        };
+        if blob.size() as u64 > MAX_FILE_SIZE {
+            return Err(not_regular_file());
+        }
+
+        let mut config: RepoConfig = toml::from_str(std::str::from_utf8(blob.content())?)?;
+        config.exclude.retain(|pattern| filters::is_valid(pattern));
+        config.exclude.sort();
+        config.exclude.dedup();
+        config.exclude.truncate(filters::MAX_PATTERNS);
+        config.category = config
+            .category
+            .map(|category| category.to_lowercase())
+            .filter(|category| CATEGORIES.contains(&category.as_str()));
+        Ok(config)
+    }
+
+    /// Compiles the `[languages]` table, skipping invalid patterns and
+    /// unknown language names.
+    pub fn language_overrides(&self) -> LanguageOverrides {
+        let mut overrides: Vec<(GlobSet, usize, LanguageType)> = self
+            .languages
+            .iter()
+            .filter_map(|(pattern, name)| {
+                let language = name.parse::<LanguageType>().ok()?;
+                if pattern.len() > filters::MAX_PATTERN_LENGTH {
+                    return None;
+                }
+                let set = filters::build_set(std::slice::from_ref(pattern)).ok()??;
+                Some((set, pattern.len(), language))
+            })
+            .take(filters::MAX_PATTERNS)
+            .collect();
+        overrides.sort_by_key(|(_, length, _)| std::cmp::Reverse(*length));
+        LanguageOverrides(overrides)
+    }
+}
+
+/// Compiled `[languages]` table, longest pattern first.
+pub struct LanguageOverrides(Vec<(GlobSet, usize, LanguageType)>);
+
+impl LanguageOverrides {
+    /// Language forced on a file, given by its path relative to the
+    /// repository root.
+    pub fn language_of(&self, path: &Path) -> Option<LanguageType> {
+        self.0
+            .iter()
+            .find(|(set, _, _)| set.is_match(path))
+            .map(|(_, _, language)| *language)
+    }
+}
+
+fn not_regular_file() -> eyre::Report {
+    eyre::eyre!("not a regular file of at most {} bytes", MAX_FILE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use git2::{FileMode, Repository};
+    use tempfile::TempDir;
+    use tokei::LanguageType;
+
+    use super::{RepoConfig, FILE_NAME, MAX_FILE_SIZE};
+
+    /// Loads a tree holding `content` as the configuration file, with `mode`.
+    fn load(mode: FileMode, content: &str) -> eyre::Result<RepoConfig> {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert(FILE_NAME, blob, mode.into()).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        RepoConfig::load(&repo, &tree)
+    }
+
+    #[test]
+    fn missing_file_is_the_default_configuration() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let config = RepoConfig::load(&repo, &tree).unwrap();
+        assert!(config.exclude.is_empty() && config.category.is_none());
+    }
+
+    #[test]
+    fn invalid_settings_are_dropped() {
+        let config = load(
+            FileMode::Blob,
+            r#"
+            exclude = ["vendor", "src/{a,b", "vendor", "**/*.generated.rs"]
+            category = "Comments"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.exclude, ["**/*.generated.rs", "vendor"]);
+        assert_eq!(config.category.as_deref(), Some("comments"));
+
+        let config = load(FileMode::Blob, r#"category = "bytes""#).unwrap();
+        assert_eq!(config.category, None);
+    }
+
+    #[test]
+    fn unparseable_files_are_rejected() {
+        assert!(load(FileMode::Blob, "exclude = [").is_err());
+        assert!(load(FileMode::Blob, "unknown = true").is_err());
+        assert!(load(FileMode::Blob, r#"exclude = "vendor""#).is_err());
+    }
+
+    #[test]
+    fn large_files_and_links_are_rejected() {
+        let padding = "#".repeat(MAX_FILE_SIZE as usize - 1);
+        assert!(load(FileMode::Blob, &format!("{}\n", padding)).is_ok());
+        assert!(load(FileMode::Blob, &format!("{}\n\n", padding)).is_err());
+        assert!(load(FileMode::Link, "../outside.toml").is_err());
+    }
+
+    #[test]
+    fn longest_language_override_wins() {
+        let config = load(
+            FileMode::Blob,
+            r#"
+            [languages]
+            "*.inc" = "PHP"
+            "include/**/*.inc" = "C++"
+            "*.h" = "NotALanguage"
+            "src/{a" = "Rust"
+            "#,
+        )
+        .unwrap();
+        let overrides = config.language_overrides();
+        let language_of = |path: &str| overrides.language_of(Path::new(path));
+        assert_eq!(language_of("lib/util.inc"), Some(LanguageType::Php));
+        assert_eq!(
+            language_of("include/deep/util.inc"),
+            Some(LanguageType::Cpp)
+        );
+        assert_eq!(language_of("include/util.h"), None);
+        assert_eq!(language_of("src/{a"), None);
+    }
+}
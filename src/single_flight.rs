@@ -0,0 +1,229 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! In-flight deduplication of expensive work ("single-flight"): concurrent
+//! callers asking for the same key share the result of a single computation
+//! instead of each running their own, e.g. when the five badges of a README
+//! are requested at once for a repository that isn't cached yet.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::OnceCell;
+
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+    /// Calls that ran the computation.
+    leaders: AtomicU64,
+    /// Calls that got the result of another call's computation.
+    coalesced: AtomicU64,
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+    fn default() -> Self {
+        SingleFlight::new()
+    }
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+            leaders: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `work` unless a call with the same `key` is already running it,
+    /// in which case that call's result is awaited and returned instead. The
+    /// boolean tells whether the result was shared.
+    ///
+    /// If the running call is cancelled (its client went away), one of the
+    /// waiting calls runs `work` in its place.
+    pub async fn run<F, Fut>(&self, key: &str, work: F) -> (T, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let entry = Entry {
+            in_flight: &self.in_flight,
+            key,
+            cell: self
+                .in_flight
+                .lock()
+                .unwrap()
+                .entry(key.to_owned())
+                .or_default()
+                .clone(),
+        };
+
+        let mut led = false;
+        let value = entry
+            .cell
+            .get_or_init(|| {
+                led = true;
+                work()
+            })
+            .await
+            .clone();
+        drop(entry);
+
+        if led {
+            self.leaders.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        (value, !led)
+    }
+
+    /// Number of calls that ran their computation.
+    pub fn leaders(&self) -> u64 {
+        self.leaders.load(Ordering::Relaxed)
+    }
+
+    /// Number of calls that shared another call's computation.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// Number of keys currently being computed.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+/// A caller's reference to the computation of `key`. Once the computation is
+/// done, the first caller to drop it removes the entry, later callers get a
+/// fresh computation (normally answered from the cache by then). The entry is
+/// also removed when its last caller is cancelled before the computation is
+/// done.
+struct Entry<'a, T> {
+    in_flight: &'a Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+    key: &'a str,
+    cell: Arc<OnceCell<T>>,
+}
+
+impl<T> Drop for Entry<'_, T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        // Callers clone the cell while holding the map's lock, so no other
+        // caller can get it once only the map and this entry hold it.
+        let finished = self.cell.initialized() || Arc::strong_count(&self.cell) == 2;
+        if finished
+            && in_flight
+                .get(self.key)
+                .is_some_and(|current| Arc::ptr_eq(current, &self.cell))
+        {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use actix_web::rt::{self, task::yield_now};
+    use tokio::sync::Notify;
+
+    use super::SingleFlight;
+
+    #[actix_web::test]
+    async fn concurrent_calls_share_one_computation() {
+        let flight = Arc::new(SingleFlight::<u32>::new());
+        let gate = Arc::new(Notify::new());
+
+        let leader = rt::spawn({
+            let (flight, gate) = (flight.clone(), gate.clone());
+            async move {
+                flight
+                    .run("key", || async move {
+                        gate.notified().await;
+                        1
+                    })
+                    .await
+            }
+        });
+        yield_now().await;
+        assert_eq!(flight.in_flight(), 1);
+
+        rt::spawn(async move {
+            yield_now().await;
+            gate.notify_one();
+        });
+        assert_eq!(flight.run("key", || async { 2 }).await, (1, true));
+        assert_eq!(leader.await.unwrap(), (1, false));
+        assert_eq!(flight.in_flight(), 0);
+        assert_eq!((flight.leaders(), flight.coalesced()), (1, 1));
+    }
+
+    #[actix_web::test]
+    async fn cancelled_call_without_waiters_is_forgotten() {
+        let flight = SingleFlight::<u32>::new();
+        let cancelled = rt::time::timeout(
+            Duration::from_millis(10),
+            flight.run("key", std::future::pending::<u32>),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(flight.in_flight(), 0);
+        assert_eq!(flight.run("key", || async { 3 }).await, (3, false));
+    }
+
+    #[actix_web::test]
+    async fn waiter_takes_over_a_cancelled_call() {
+        let flight = Arc::new(SingleFlight::<u32>::new());
+        let leader = rt::spawn({
+            let flight = flight.clone();
+            async move { flight.run("key", std::future::pending::<u32>).await }
+        });
+        yield_now().await;
+
+        let gate = Arc::new(Notify::new());
+        let waiter = rt::spawn({
+            let (flight, gate) = (flight.clone(), gate.clone());
+            async move {
+                flight
+                    .run("key", || async move {
+                        gate.notified().await;
+                        2
+                    })
+                    .await
+            }
+        });
+        yield_now().await;
+        leader.abort();
+        yield_now().await;
+        // The waiter now runs its own computation, which is still in flight.
+        assert_eq!(flight.in_flight(), 1);
+
+        gate.notify_one();
+        assert_eq!(waiter.await.unwrap(), (2, false));
+        assert_eq!(flight.in_flight(), 0);
+    }
+}
@@ -0,0 +1,482 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Counting of a commit straight from the git object database: its tree is
+//! walked and the bytes of each blob are handed to tokei, so that nothing is
+//! checked out to disk. The walk skips the same files as tokei's own: hidden
+//! files and directories unless `hidden` is set, and the files matched by the
+//! `.tokeignore`, `.ignore` and `.gitignore` files of the tree according to
+//! the `no_ignore*` options. Symbolic links and submodules are never counted.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use cached::{Cached, SizedCache};
+use git2::{ObjectType, Oid, Repository, Tree};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use once_cell::sync::Lazy;
+use tokei::{Language, LanguageType, Report};
+
+use crate::{
+    blob_cache::{BlobCache, BlobKey},
+    filters::GlobFilter,
+    repo_config::LanguageOverrides,
+};
+
+/// Ignore files in decreasing order of precedence, as in tokei.
+const IGNORE_FILES: [&str; 3] = [".tokeignore", ".ignore", ".gitignore"];
+
+/// Ignore files of one directory, indexed like `IGNORE_FILES`.
+type IgnoreLevel = [Option<Gitignore>; 3];
+
+/// Name of the file of the probe directory holding a shebang line. Having an
+/// extension, it can't be mistaken for a file of the tree without one.
+const SHEBANG_PROBE: &str = "shebang.probe";
+
+/// Languages of the shebang lines met so far, by their first two words (all
+/// tokei looks at), so that only new shebangs are written to the probe file.
+static SHEBANGS: Lazy<Mutex<SizedCache<String, Option<LanguageType>>>> =
+    Lazy::new(|| Mutex::new(SizedCache::with_size(1024)));
+
+pub struct TreeCounter<'a> {
+    repo: &'a Repository,
+    config: &'a tokei::Config,
+    filter: &'a GlobFilter,
+    overrides: &'a LanguageOverrides,
+    blobs: &'a BlobCache,
+    /// Scratch directory where shebang lines are written, tokei only
+    /// detecting shebangs in files.
+    probe_dir: &'a Path,
+    languages: BTreeMap<LanguageType, Language>,
+}
+
+impl<'a> TreeCounter<'a> {
+    pub fn new(
+        repo: &'a Repository,
+        config: &'a tokei::Config,
+        filter: &'a GlobFilter,
+        overrides: &'a LanguageOverrides,
+        blobs: &'a BlobCache,
+        probe_dir: &'a Path,
+    ) -> Self {
+        TreeCounter {
+            repo,
+            config,
+            filter,
+            overrides,
+            blobs,
+            probe_dir,
+            languages: BTreeMap::new(),
+        }
+    }
+
+    /// Counts the files of `tree` under `path` (relative to the root of the
+    /// tree, the whole tree when `None`). Report names are relative to the
+    /// root of the tree.
+    pub fn count(
+        mut self,
+        tree: &Tree<'_>,
+        path: Option<&str>,
+    ) -> eyre::Result<BTreeMap<LanguageType, Language>> {
+        let mut levels: Vec<IgnoreLevel> = Vec::new();
+        let mut tree = tree.clone();
+        let mut dir = PathBuf::new();
+        for component in path.into_iter().flat_map(|path| path.split('/')) {
+            if !self.config.no_ignore_parent.unwrap_or(false) {
+                levels.push(self.ignore_level(&tree, &dir)?);
+            }
+            // Only an actual directory can be counted, not a symbolic link.
+            let id = tree
+                .get_name(component)
+                .filter(|entry| entry.kind() == Some(ObjectType::Tree))
+                .map(|entry| entry.id())
+                .ok_or_else(|| eyre::eyre!("Invalid path provided."))?;
+            tree = self.repo.find_tree(id)?;
+            dir.push(component);
+        }
+        self.walk(&tree, &dir, &mut levels)?;
+
+        for language in self.languages.values_mut() {
+            language.total();
+        }
+        Ok(self.languages)
+    }
+
+    /// Counts the files of the directory `dir`, whose parents' ignore files
+    /// are in `levels`.
+    fn walk(
+        &mut self,
+        tree: &Tree<'_>,
+        dir: &Path,
+        levels: &mut Vec<IgnoreLevel>,
+    ) -> eyre::Result<()> {
+        levels.push(self.ignore_level(tree, dir)?);
+        let hidden = self.config.hidden.unwrap_or(false);
+        for entry in tree.iter() {
+            // Paths that aren't UTF-8 can't be matched by the patterns.
+            let Some(name) = entry.name() else {
+                continue;
+            };
+            if name.starts_with('.') && !hidden {
+                continue;
+            }
+            let path = dir.join(name);
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    if self.filter.is_excluded(&path) || is_ignored(levels, &path, true) {
+                        continue;
+                    }
+                    let subtree = self.repo.find_tree(entry.id())?;
+                    self.walk(&subtree, &path, levels)?;
+                }
+                Some(ObjectType::Blob) if entry.filemode() != i32::from(git2::FileMode::Link) => {
+                    if !self.filter.is_match(&path) || is_ignored(levels, &path, false) {
+                        continue;
+                    }
+                    self.count_file(path, entry.id())?;
+                }
+                _ => {}
+            }
+        }
+        levels.pop();
+        Ok(())
+    }
+
+    /// Detects the language of a file, then parses it unless the language is
+    /// filtered out or the blob was counted before.
+    fn count_file(&mut self, path: PathBuf, id: Oid) -> eyre::Result<()> {
+        let mut blob = None;
+        let language = match self.overrides.language_of(&path) {
+            Some(language) => Some(language),
+            // Detected from the name alone: the file is only read by tokei for
+            // its shebang when the name has no extension.
+            None if path.extension().is_some() => LanguageType::from_path(&path, self.config),
+            None => {
+                let found = self.repo.find_blob(id)?;
+                let language = self.detect_shebang(&path, found.content())?;
+                blob = Some(found);
+                language
+            }
+        };
+        let Some(language) = language else {
+            return Ok(());
+        };
+        if self
+            .config
+            .types
+            .as_ref()
+            .is_some_and(|types| !types.contains(&language))
+        {
+            return Ok(());
+        }
+
+        let key = BlobKey::new(id, language, self.config);
+        let stats = match self.blobs.get(&key) {
+            Some(stats) => stats,
+            None => {
+                let blob = match blob {
+                    Some(blob) => blob,
+                    None => self.repo.find_blob(id)?,
+                };
+                let stats = language.parse_from_slice(blob.content(), self.config);
+                self.blobs.insert(key, &stats);
+                stats
+            }
+        };
+        let mut report = Report::new(path);
+        report += stats;
+        self.languages
+            .entry(language)
+            .or_default()
+            .add_report(report);
+        Ok(())
+    }
+
+    /// Detects the language of a file without an extension from its name or
+    /// shebang. Names (`Makefile`, `Dockerfile`...) are looked up by handing
+    /// tokei a path that doesn't exist; a shebang tokei hasn't been shown yet
+    /// is written to the probe file for it to read.
+    fn detect_shebang(&self, path: &Path, content: &[u8]) -> eyre::Result<Option<LanguageType>> {
+        let Some(name) = path.file_name() else {
+            return Ok(None);
+        };
+        if let Some(language) = LanguageType::from_path(self.probe_dir.join(name), self.config) {
+            return Ok(Some(language));
+        }
+        // tokei reads the first line as UTF-8, and only matches its first
+        // word against shebangs.
+        let first_line = content.split(|byte| *byte == b'\n').next().unwrap_or(&[]);
+        let Ok(first_line) = std::str::from_utf8(first_line) else {
+            return Ok(None);
+        };
+        if !first_line.starts_with("#!") {
+            return Ok(None);
+        }
+        let shebang = first_line
+            .split_whitespace()
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if let Some(language) = SHEBANGS.lock().unwrap().cache_get(&shebang) {
+            return Ok(*language);
+        }
+        let probe = self.probe_dir.join(SHEBANG_PROBE);
+        std::fs::write(&probe, &shebang)?;
+        let language = LanguageType::from_shebang(&probe);
+        SHEBANGS.lock().unwrap().cache_set(shebang, language);
+        Ok(language)
+    }
+
+    /// Compiles the ignore files of the directory `dir` that the options
+    /// honour. Invalid lines are skipped, as by git.
+    fn ignore_level(&self, tree: &Tree<'_>, dir: &Path) -> eyre::Result<IgnoreLevel> {
+        let mut level: IgnoreLevel = Default::default();
+        if self.config.no_ignore.unwrap_or(false) {
+            return Ok(level);
+        }
+        for (index, name) in IGNORE_FILES.iter().enumerate() {
+            if *name == ".gitignore" && self.config.no_ignore_vcs.unwrap_or(false) {
+                continue;
+            }
+            let Some(entry) = tree.get_name(name) else {
+                continue;
+            };
+            if entry.kind() != Some(ObjectType::Blob)
+                || entry.filemode() == i32::from(git2::FileMode::Link)
+            {
+                continue;
+            }
+            let blob = self.repo.find_blob(entry.id())?;
+            let mut builder = GitignoreBuilder::new(dir);
+            for line in String::from_utf8_lossy(blob.content()).lines() {
+                let _ = builder.add_line(None, line);
+            }
+            level[index] = builder.build().ok();
+        }
+        Ok(level)
+    }
+}
+
+/// Whether a path is ignored by the ignore files of its directory and its
+/// parents: the first kind of ignore file matching it decides, the deepest
+/// one first within a kind.
+fn is_ignored(levels: &[IgnoreLevel], path: &Path, is_dir: bool) -> bool {
+    for index in 0..IGNORE_FILES.len() {
+        for level in levels.iter().rev() {
+            let Some(gitignore) = &level[index] else {
+                continue;
+            };
+            let matched = gitignore.matched(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use git2::{FileMode, Oid, Repository};
+    use tempfile::TempDir;
+
+    use super::TreeCounter;
+    use crate::{blob_cache::BlobCache, filters::GlobFilter, repo_config::RepoConfig};
+
+    const FILE: i32 = 0o100644;
+
+    /// Writes a tree of `(path, mode, content)` entries into `repo`. The
+    /// content of a submodule is the ID of its commit.
+    fn build_tree(repo: &Repository, entries: &[(&str, i32, &str)]) -> Oid {
+        let mut builder = repo.treebuilder(None).unwrap();
+        let mut dirs: BTreeMap<&str, Vec<(&str, i32, &str)>> = BTreeMap::new();
+        for &(path, mode, content) in entries {
+            match path.split_once('/') {
+                Some((dir, rest)) => dirs.entry(dir).or_default().push((rest, mode, content)),
+                None if mode == i32::from(FileMode::Commit) => {
+                    let commit = Oid::from_str(content).unwrap();
+                    builder.insert(path, commit, mode).unwrap();
+                }
+                None => {
+                    let blob = repo.blob(content.as_bytes()).unwrap();
+                    builder.insert(path, blob, mode).unwrap();
+                }
+            }
+        }
+        for (dir, entries) in dirs {
+            let tree = build_tree(repo, &entries);
+            builder.insert(dir, tree, FileMode::Tree.into()).unwrap();
+        }
+        builder.write().unwrap()
+    }
+
+    /// Counts a tree made of `entries`, returning the files found by language.
+    fn count(
+        entries: &[(&str, i32, &str)],
+        config: tokei::Config,
+    ) -> BTreeMap<String, Vec<String>> {
+        count_path(entries, config, None)
+    }
+
+    /// Counts the files of a tree under `path`, see `count`.
+    fn count_path(
+        entries: &[(&str, i32, &str)],
+        config: tokei::Config,
+        path: Option<&str>,
+    ) -> BTreeMap<String, Vec<String>> {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init_bare(dir.path().join("repository.git")).unwrap();
+        let probe_dir = dir.path().join("scratch");
+        std::fs::create_dir(&probe_dir).unwrap();
+        let tree = repo.find_tree(build_tree(&repo, entries)).unwrap();
+        let filter = GlobFilter::new(&[], &[]).unwrap();
+        let overrides = RepoConfig::default().language_overrides();
+        let blobs = BlobCache::new(0);
+        let languages = TreeCounter::new(&repo, &config, &filter, &overrides, &blobs, &probe_dir)
+            .count(&tree, path)
+            .unwrap();
+        languages
+            .into_iter()
+            .map(|(language, stats)| {
+                let mut files: Vec<String> = stats
+                    .reports
+                    .iter()
+                    .map(|report| report.name.display().to_string())
+                    .collect();
+                files.sort();
+                (language.name().to_owned(), files)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_names_and_shebangs_of_files_without_an_extension() {
+        let counted = count(
+            &[
+                ("Makefile", FILE, "all:\n\ttrue\n"),
+                ("bin/build", FILE, "#!/usr/bin/env python3\nprint()\n"),
+                ("bin/run", FILE, "#!/bin/bash\necho\n"),
+                ("bin/tool", FILE, "#!/usr/bin/env python3\nprint()\n"),
+                ("LICENSE", FILE, "MIT\n"),
+            ],
+            tokei::Config::default(),
+        );
+        assert_eq!(counted["Makefile"], ["Makefile"]);
+        assert_eq!(counted["Python"], ["bin/build", "bin/tool"]);
+        assert_eq!(counted["BASH"], ["bin/run"]);
+        assert_eq!(counted.len(), 3);
+    }
+
+    /// Python files ignored by the root `.gitignore`, some of them brought
+    /// back by other ignore files.
+    const IGNORED: &[(&str, i32, &str)] = &[
+        (".gitignore", FILE, "*.py\n"),
+        (".ignore", FILE, "!tool.py\n"),
+        (".tokeignore", FILE, "generated.rs\n"),
+        ("main.py", FILE, "print()\n"),
+        ("tool.py", FILE, "print()\n"),
+        ("generated.rs", FILE, "fn main() {}\n"),
+        ("main.rs", FILE, "fn main() {}\n"),
+        ("sub/.gitignore", FILE, "!keep.py\n"),
+        ("sub/keep.py", FILE, "print()\n"),
+        ("sub/drop.py", FILE, "print()\n"),
+    ];
+
+    #[test]
+    fn ignore_files_apply_by_kind_then_depth() {
+        let counted = count(IGNORED, tokei::Config::default());
+        // `.ignore` overrides `.gitignore`, and a deeper `.gitignore`
+        // overrides the root one.
+        assert_eq!(counted["Python"], ["sub/keep.py", "tool.py"]);
+        assert_eq!(counted["Rust"], ["main.rs"]);
+    }
+
+    #[test]
+    fn ignore_options_are_honoured() {
+        let no_vcs = count(
+            IGNORED,
+            tokei::Config {
+                no_ignore_vcs: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            no_vcs["Python"],
+            ["main.py", "sub/drop.py", "sub/keep.py", "tool.py"]
+        );
+        assert_eq!(no_vcs["Rust"], ["main.rs"]);
+
+        let none = count(
+            IGNORED,
+            tokei::Config {
+                no_ignore: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(none["Rust"], ["generated.rs", "main.rs"]);
+
+        let sub = count_path(IGNORED, tokei::Config::default(), Some("sub"));
+        assert_eq!(sub["Python"], ["sub/keep.py"]);
+        let no_parent = count_path(
+            IGNORED,
+            tokei::Config {
+                no_ignore_parent: Some(true),
+                ..Default::default()
+            },
+            Some("sub"),
+        );
+        assert_eq!(no_parent["Python"], ["sub/drop.py", "sub/keep.py"]);
+    }
+
+    #[test]
+    fn hidden_files_links_and_submodules_are_skipped() {
+        let submodule = "1".repeat(40);
+        let entries: &[(&str, i32, &str)] = &[
+            ("main.rs", FILE, "fn main() {}\n"),
+            (".hidden.rs", FILE, "fn main() {}\n"),
+            (".config/build.rs", FILE, "fn main() {}\n"),
+            ("link.rs", FileMode::Link.into(), "main.rs"),
+            ("vendor.rs", FileMode::Commit.into(), &submodule),
+        ];
+        let counted = count(entries, tokei::Config::default());
+        assert_eq!(counted["Rust"], ["main.rs"]);
+        let hidden = count(
+            entries,
+            tokei::Config {
+                hidden: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            hidden["Rust"],
+            [".config/build.rs", ".hidden.rs", "main.rs"]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Watch list of repositories kept precomputed, so that their badges never
+//! wait for a clone. Every interval, the refs of each watched repository are
+//! listed and the heads of its branches that aren't cached yet (typically
+//! because they moved) are counted, one at a time so that badge requests
+//! keep most of the worker pool. The file is read again before every pass:
+//!
+//! ```toml
+//! [[repository]]
+//! # `domain/user/repo`, as in badge URLs.
+//! repository = "github.com/sctg-development/tokeisrv"
+//! # Branches to keep counted, the default branch when omitted.
+//! branches = ["main", "develop"]
+//! # Count options as badge query strings, each precomputed for every branch.
+//! # Defaults to the options of a badge without query parameters.
+//! queries = ["", "path=src&exclude=*.test.rs"]
+//! ```
+
+use std::{path::Path, time::Duration};
+
+use actix_web::web;
+
+use crate::{
+    cache_get, count_on_pool, list_remote_refs, pool_outcome, repository_url, resolve_branch,
+    statistics_key, AppConfig, CountOutcome, RequestedRef, StatisticsQuery, TokeiOptions,
+};
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchFile {
+    #[serde(default)]
+    repository: Vec<WatchEntry>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WatchEntry {
+    repository: String,
+    #[serde(default)]
+    branches: Vec<String>,
+    #[serde(default)]
+    queries: Vec<String>,
+}
+
+/// A validated watch list entry.
+struct Watched {
+    domain: String,
+    user: String,
+    repo: String,
+    /// Branch names, an empty name standing for the default branch.
+    branches: Vec<String>,
+    queries: Vec<StatisticsQuery>,
+}
+
+/// Contents of a watch list file, see the module documentation.
+pub struct WatchList(Vec<Watched>);
+
+impl WatchList {
+    /// Reads and validates a watch list file. Query strings may not select a
+    /// reference: branches are listed on their own.
+    pub fn load(path: &Path) -> eyre::Result<WatchList> {
+        let file: WatchFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+        let mut watched = Vec::with_capacity(file.repository.len());
+        for entry in file.repository {
+            let mut parts = entry.repository.trim_matches('/').splitn(3, '/');
+            let (Some(domain), Some(user), Some(repo)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(eyre::eyre!(
+                    "{}: expected domain/user/repo",
+                    entry.repository
+                ));
+            };
+            let queries = if entry.queries.is_empty() {
+                vec![String::new()]
+            } else {
+                entry.queries
+            };
+            let queries = queries
+                .iter()
+                .map(|query| {
+                    parse_query(query).map_err(|e| eyre::eyre!("{}: {}", entry.repository, e))
+                })
+                .collect::<eyre::Result<Vec<StatisticsQuery>>>()?;
+            watched.push(Watched {
+                domain: domain.to_owned(),
+                user: user.to_owned(),
+                repo: repo.to_owned(),
+                branches: if entry.branches.is_empty() {
+                    vec![String::new()]
+                } else {
+                    entry.branches
+                },
+                queries,
+            });
+        }
+        Ok(WatchList(watched))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Parses a query string of count options, rejecting invalid options and
+/// references.
+fn parse_query(query: &str) -> eyre::Result<StatisticsQuery> {
+    let parsed = web::Query::<StatisticsQuery>::from_query(query)
+        .map_err(|e| eyre::eyre!("{}: {}", query, e))?
+        .into_inner();
+    let invalid = |e: actix_web::Error| eyre::eyre!("{}: {}", query, e);
+    parsed
+        .count_options(&TokeiOptions::default())
+        .map_err(invalid)?;
+    match parsed.requested_ref().map_err(invalid)? {
+        RequestedRef::Default => Ok(parsed),
+        _ => Err(eyre::eyre!("{}: use branches to select a reference", query)),
+    }
+}
+
+/// Precomputes the watch list every `interval`, starting right away, and
+/// reloads it from `path` in between. An invalid file keeps the previous list.
+pub async fn run(
+    data: web::Data<AppConfig>,
+    path: std::path::PathBuf,
+    mut list: WatchList,
+    interval: Duration,
+) {
+    loop {
+        for watched in &list.0 {
+            if let Err(e) = precompute(&data, watched).await {
+                log::warn!(
+                    "{}/{}/{} - Unable to precompute: {}",
+                    watched.domain,
+                    watched.user,
+                    watched.repo,
+                    e
+                );
+            }
+        }
+        actix_web::rt::time::sleep(interval).await;
+        match WatchList::load(&path) {
+            Ok(reloaded) => list = reloaded,
+            Err(e) => log::warn!(
+                "Keeping the previous watch list, unable to read {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Lists the refs of a watched repository and counts each branch head and
+/// query that isn't cached, waiting for every count.
+async fn precompute(data: &web::Data<AppConfig>, watched: &Watched) -> actix_web::Result<()> {
+    let Some(url) = repository_url(data, &watched.domain, &watched.user, &watched.repo)? else {
+        return Ok(());
+    };
+    let remote_url = url.clone();
+    let listed = data
+        .workers
+        .run(move || list_remote_refs(&remote_url))
+        .await;
+    let remote = match pool_outcome(listed, &url, &data.workers)? {
+        Some(listed) => listed.map_err(actix_web::error::ErrorBadRequest)?,
+        // Retried on the next pass.
+        None => return Ok(()),
+    };
+
+    for branch in &watched.branches {
+        // A missing branch doesn't keep the others from being precomputed.
+        let (branch, sha) = match resolve_branch(&remote, &url, branch) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                log::warn!("{}#{} - Skipping the watched branch: {}", url, branch, e);
+                continue;
+            }
+        };
+        for query in &watched.queries {
+            let options = query.count_options(&data.tokei_defaults)?;
+            let key = statistics_key(
+                &url,
+                &sha,
+                &branch,
+                data.ignore_filetypes.as_ref(),
+                &options,
+            );
+            if cache_get(data, &key).await.is_some() {
+                continue;
+            }
+            log::info!("{}#{}#{} Precomputing watched repository", url, sha, branch);
+            let (outcome, _) = data
+                .in_flight
+                .run(&key, || {
+                    count_on_pool(data, &key, &url, &sha, &branch, &options)
+                })
+                .await;
+            match outcome {
+                CountOutcome::Counted(_) => {}
+                CountOutcome::Busy => return Ok(()),
+                CountOutcome::Invalid(message) | CountOutcome::Failed(message) => {
+                    log::warn!("{}#{}#{} - Unable to count: {}", url, sha, branch, message)
+                }
+            }
+        }
+    }
+    Ok(())
+}
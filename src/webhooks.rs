@@ -0,0 +1,381 @@
+// MIT License (MIT)
+
+// Copyright (c) 2025 Ronan Le Meillat for SCTG Development
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Push webhooks (`/hooks/...`) recounting a branch as soon as it is pushed
+//! to, so that its badges are already cached when someone views them. They
+//! are only served when a webhook secret is configured (`--webhook-secret`).
+
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+use crate::{
+    admin::constant_time_eq, repository_url, warm_commit, AppConfig, CountOptions,
+    ResolvedRepository, HASH_LENGTH,
+};
+
+/// Largest accepted payload: GitHub caps webhook payloads at 25 MB.
+const MAX_PAYLOAD_SIZE: usize = 25 * 1024 * 1024;
+
+/// Git server sending a webhook, each with its own headers and payload.
+#[derive(Clone, Copy)]
+enum Provider {
+    GitHub,
+    GitLab,
+    Gitea,
+}
+
+impl Provider {
+    fn name(self) -> &'static str {
+        match self {
+            Provider::GitHub => "GitHub",
+            Provider::GitLab => "GitLab",
+            Provider::Gitea => "Gitea",
+        }
+    }
+
+    /// Whether the request proves the knowledge of `secret`: an HMAC-SHA256
+    /// signature of the body for GitHub and Gitea, the secret itself for
+    /// GitLab.
+    fn verify(self, request: &HttpRequest, body: &[u8], secret: &str) -> bool {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+        let signature = match self {
+            Provider::GitHub => match header("x-hub-signature-256").strip_prefix("sha256=") {
+                Some(signature) => signature,
+                None => return false,
+            },
+            Provider::Gitea => header("x-gitea-signature"),
+            Provider::GitLab => {
+                return constant_time_eq(header("x-gitlab-token").as_bytes(), secret.as_bytes())
+            }
+        };
+        match hmac_sha256(secret.as_bytes(), body) {
+            Ok(expected) => constant_time_eq(
+                signature.to_ascii_lowercase().as_bytes(),
+                expected.as_bytes(),
+            ),
+            Err(e) => {
+                log::error!("Unable to verify a {} webhook: {}", self.name(), e);
+                false
+            }
+        }
+    }
+
+    fn is_push(self, request: &HttpRequest) -> bool {
+        let (name, push) = match self {
+            Provider::GitHub => ("x-github-event", "push"),
+            Provider::GitLab => ("x-gitlab-event", "Push Hook"),
+            Provider::Gitea => ("x-gitea-event", "push"),
+        };
+        request
+            .headers()
+            .get(name)
+            .is_some_and(|value| value.as_bytes() == push.as_bytes())
+    }
+
+    /// Web URL of the pushed repository, e.g. `https://github.com/user/repo`.
+    fn repository_url(self, event: &serde_json::Value) -> Option<&str> {
+        match self {
+            Provider::GitHub | Provider::Gitea => event["repository"]["html_url"].as_str(),
+            Provider::GitLab => event["project"]["web_url"].as_str(),
+        }
+    }
+}
+
+/// Lowercase hexadecimal HMAC-SHA256 of `body`.
+fn hmac_sha256(secret: &[u8], body: &[u8]) -> Result<String, openssl::error::ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(body)?;
+    Ok(signer
+        .sign_to_vec()?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Branch pushed to and its new head, as found in a push event.
+struct Push<'a> {
+    domain: &'a str,
+    user: &'a str,
+    repo: &'a str,
+    branch: &'a str,
+    sha: &'a str,
+}
+
+impl<'a> Push<'a> {
+    /// Reads a push event, `None` for tag pushes, deleted branches, payloads
+    /// missing a field and repositories of nested groups (e.g. GitLab
+    /// subgroups), which no badge route can address.
+    fn parse(provider: Provider, event: &'a serde_json::Value) -> Option<Self> {
+        let branch = event["ref"].as_str()?.strip_prefix("refs/heads/")?;
+        let sha = event["after"].as_str()?;
+        if sha.len() != HASH_LENGTH
+            || !sha.chars().all(|c| c.is_ascii_hexdigit())
+            || sha.chars().all(|c| c == '0')
+        {
+            return None;
+        }
+        let url = provider.repository_url(event)?;
+        let path = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))?;
+        let (domain, path) = path.split_once('/')?;
+        let (user, repo) = path.trim_end_matches('/').split_once('/')?;
+        if repo.contains('/') {
+            log::info!(
+                "{} - Ignoring a push to a repository of a nested group",
+                url
+            );
+            return None;
+        }
+        Some(Push {
+            domain,
+            user,
+            repo,
+            branch,
+            sha,
+        })
+    }
+}
+
+/// Verifies a webhook and, for a push to a branch, starts counting its new
+/// head with the server's default count options, the ones of a badge without
+/// query parameters.
+async fn receive(
+    provider: Provider,
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    let Some(secret) = &data.webhook_secret else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    if !provider.verify(&request, &body, secret) {
+        log::warn!(
+            "Rejected {} webhook with an invalid signature",
+            provider.name()
+        );
+        return Ok(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "invalid signature"
+        })));
+    }
+    let ignored = || HttpResponse::Ok().json(serde_json::json!({ "status": "ignored" }));
+    if !provider.is_push(&request) {
+        return Ok(ignored());
+    }
+    let event: serde_json::Value =
+        serde_json::from_slice(&body).map_err(actix_web::error::ErrorBadRequest)?;
+    let Some(push) = Push::parse(provider, &event) else {
+        return Ok(ignored());
+    };
+    let Some(url) = repository_url(&data, push.domain, push.user, push.repo)? else {
+        return Ok(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "forbidden"
+        })));
+    };
+    log::info!(
+        "{}#{}#{} Pushed, received from {}",
+        url,
+        push.sha,
+        push.branch,
+        provider.name()
+    );
+    let resolved = ResolvedRepository {
+        url,
+        sha: push.sha.to_ascii_lowercase(),
+        branch: push.branch.to_owned(),
+        tag: None,
+    };
+    let options = CountOptions {
+        tokei: data.tokei_defaults.clone(),
+        ..CountOptions::default()
+    };
+    let (key, started) = warm_commit(&data, &resolved, &options).await;
+    let status = if started { "started" } else { "cached" };
+    let body = serde_json::json!({ "status": status, "key": key });
+    Ok(if started {
+        HttpResponse::Accepted().json(body)
+    } else {
+        HttpResponse::Ok().json(body)
+    })
+}
+
+/// GitHub push webhook, signed with `X-Hub-Signature-256`.
+#[post("/github")]
+pub async fn github(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    receive(Provider::GitHub, request, data, body).await
+}
+
+/// GitLab push webhook, authenticated with `X-Gitlab-Token`.
+#[post("/gitlab")]
+pub async fn gitlab(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    receive(Provider::GitLab, request, data, body).await
+}
+
+/// Gitea (and Forgejo) push webhook, signed with `X-Gitea-Signature`.
+#[post("/gitea")]
+pub async fn gitea(
+    request: HttpRequest,
+    data: web::Data<AppConfig>,
+    body: web::Bytes,
+) -> actix_web::Result<HttpResponse> {
+    receive(Provider::Gitea, request, data, body).await
+}
+
+/// The webhooks, under `/hooks`. Only they accept payloads of up to
+/// `MAX_PAYLOAD_SIZE`, other routes keep actix's default limit.
+pub fn scope() -> actix_web::Scope {
+    web::scope("/hooks")
+        .app_data(web::PayloadConfig::new(MAX_PAYLOAD_SIZE))
+        .service(github)
+        .service(gitlab)
+        .service(gitea)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{hmac_sha256, Provider, Push};
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const BODY: &[u8] = b"Hello, World!";
+    /// Signature of `BODY` from GitHub's documentation.
+    const SIGNATURE: &str = "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn verify(provider: Provider, header: (&str, &str), body: &[u8]) -> bool {
+        let request = TestRequest::default()
+            .insert_header(header)
+            .to_http_request();
+        provider.verify(&request, body, SECRET)
+    }
+
+    #[test]
+    fn hmac_matches_github_example() {
+        assert_eq!(hmac_sha256(SECRET.as_bytes(), BODY).unwrap(), SIGNATURE);
+    }
+
+    #[test]
+    fn github_signature_is_verified() {
+        let header = "x-hub-signature-256";
+        let signed = format!("sha256={}", SIGNATURE);
+        assert!(verify(Provider::GitHub, (header, &signed), BODY));
+        let uppercase = format!("sha256={}", SIGNATURE.to_uppercase());
+        assert!(verify(Provider::GitHub, (header, &uppercase), BODY));
+        assert!(!verify(Provider::GitHub, (header, SIGNATURE), BODY));
+        assert!(!verify(
+            Provider::GitHub,
+            (header, &signed),
+            b"Hello, World?"
+        ));
+        assert!(!verify(
+            Provider::GitHub,
+            ("x-gitea-signature", SIGNATURE),
+            BODY
+        ));
+    }
+
+    #[test]
+    fn gitea_signature_is_verified() {
+        assert!(verify(
+            Provider::Gitea,
+            ("x-gitea-signature", SIGNATURE),
+            BODY
+        ));
+        assert!(!verify(
+            Provider::Gitea,
+            ("x-gitea-signature", &SIGNATURE[1..]),
+            BODY
+        ));
+        assert!(!verify(Provider::Gitea, ("x-gitea-signature", ""), BODY));
+    }
+
+    #[test]
+    fn gitlab_token_is_compared() {
+        assert!(verify(Provider::GitLab, ("x-gitlab-token", SECRET), BODY));
+        assert!(!verify(
+            Provider::GitLab,
+            ("x-gitlab-token", "It's a secret"),
+            BODY
+        ));
+        assert!(!verify(
+            Provider::GitLab,
+            ("x-hub-signature-256", SECRET),
+            BODY
+        ));
+    }
+
+    #[test]
+    fn only_branch_pushes_are_parsed() {
+        let sha = "a".repeat(40);
+        let event = serde_json::json!({
+            "ref": "refs/heads/main",
+            "after": sha,
+            "repository": { "html_url": "https://github.com/user/repo" },
+        });
+        let push = Push::parse(Provider::GitHub, &event).unwrap();
+        assert_eq!(
+            (push.domain, push.user, push.repo, push.branch, push.sha),
+            ("github.com", "user", "repo", "main", sha.as_str())
+        );
+
+        let mut tag = event.clone();
+        tag["ref"] = "refs/tags/v1".into();
+        assert!(Push::parse(Provider::GitHub, &tag).is_none());
+        let mut deleted = event.clone();
+        deleted["after"] = "0".repeat(40).into();
+        assert!(Push::parse(Provider::GitHub, &deleted).is_none());
+        assert!(Push::parse(Provider::GitLab, &event).is_none());
+    }
+
+    #[test]
+    fn pushes_to_nested_groups_are_ignored() {
+        let event = |web_url: &str| {
+            serde_json::json!({
+                "ref": "refs/heads/main",
+                "after": "b".repeat(40),
+                "project": { "web_url": web_url },
+            })
+        };
+        let project = event("https://gitlab.com/group/repo/");
+        let push = Push::parse(Provider::GitLab, &project).unwrap();
+        assert_eq!((push.user, push.repo), ("group", "repo"));
+        let subgroup = event("https://gitlab.com/group/sub/repo");
+        assert!(Push::parse(Provider::GitLab, &subgroup).is_none());
+        let no_group = event("https://gitlab.com/repo");
+        assert!(Push::parse(Provider::GitLab, &no_group).is_none());
+    }
+}